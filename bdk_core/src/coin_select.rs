@@ -1,10 +1,16 @@
 use bitcoin::{Transaction, TxOut};
 
-use crate::{BTreeSet, Vec};
+use crate::{BTreeSet, FeeRate, Vec};
 
-const TXIN_BASE_WEIGHT: u32 = (32 + 4 + 4 + 1) * 4;
+/// The weight of an input without its satisfaction (outpoint, sequence and script length).
+pub const TXIN_BASE_WEIGHT: u32 = (32 + 4 + 4 + 1) * 4;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct CoinSelector {
     candidates: Vec<WeightedValue>,
     selected: BTreeSet<usize>,
@@ -12,23 +18,40 @@ pub struct CoinSelector {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct WeightedValue {
     pub value: u64,
     pub weight: u32,
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct CoinSelectorOpt {
     /// The value we need to select.
     pub target_value: u64,
-    /// The feerate we should try and achieve in sats per weight unit.
-    pub target_feerate: f32,
+    /// The feerate we should try and achieve.
+    pub target_feerate: FeeRate,
     /// The minimum absolute fee.
     pub min_absolute_fee: u64,
     /// The weight of the template transaction including fixed inputs and outputs.
     pub base_weight: u32,
     /// The weight of the drain (change) output.
     pub drain_weight: u32,
+    /// The weight of the input that will spend the drain output later.
+    pub drain_spend_weight: u32,
+    /// The smallest drain (change) value worth creating. Less than this is given up as fees.
+    pub min_drain_value: u64,
+    /// The feerate we expect to pay in the long run. Spending inputs now when the target feerate
+    /// is below this saves fees later and vice versa.
+    pub long_term_feerate: FeeRate,
     /// The input value of the template transaction.
     pub starting_input_value: u64,
 }
@@ -37,11 +60,14 @@ impl CoinSelectorOpt {
     pub fn from_weights(base_weight: u32, drain_weight: u32) -> Self {
         Self {
             target_value: 0,
-            // by defualt 1 sat per byte (i.e. 4 per wu)
-            target_feerate: 4.0,
+            target_feerate: FeeRate::DEFAULT_MIN_RELAY,
             min_absolute_fee: 0,
             base_weight,
             drain_weight,
+            drain_spend_weight: 0,
+            min_drain_value: 0,
+            // the same as the default target feerate so spending inputs isn't wasteful by default
+            long_term_feerate: FeeRate::DEFAULT_MIN_RELAY,
             starting_input_value: 0,
         }
     }
@@ -78,6 +104,10 @@ impl CoinSelector {
         self.selected.insert(index);
     }
 
+    pub fn deselect(&mut self, index: usize) -> bool {
+        self.selected.remove(&index)
+    }
+
     pub fn current_weight(&self) -> u32 {
         self.opts.base_weight
             + self
@@ -129,27 +159,27 @@ impl CoinSelector {
 
         let inputs_minus_outputs = self.current_value() - self.opts.target_value;
 
-        // check fee rate satisfied
-        let feerate_without_change = inputs_minus_outputs as f32 / base_weight as f32;
-
-        // we simply don't have enough fee to acheieve the feerate
-        if feerate_without_change < self.opts.target_feerate {
-            return None;
-        }
-
-        if inputs_minus_outputs < self.opts.min_absolute_fee {
-            return None;
-        }
-
         let weight_with_change = base_weight + self.opts.drain_weight;
-        let target_fee_with_change = ((self.opts.target_feerate * weight_with_change as f32).ceil()
-            as u64)
+        let target_fee_with_change = self
+            .opts
+            .target_feerate
+            .fee_for_weight(weight_with_change)
             .max(self.opts.min_absolute_fee);
-        let target_fee_without_change = ((self.opts.target_feerate * base_weight as f32).ceil()
-            as u64)
+        let target_fee_without_change = self
+            .opts
+            .target_feerate
+            .fee_for_weight(base_weight)
             .max(self.opts.min_absolute_fee);
 
-        let (excess, use_change) = match inputs_minus_outputs.checked_sub(target_fee_with_change) {
+        // we simply don't have enough fee to achieve the feerate or the minimum absolute fee
+        if inputs_minus_outputs < target_fee_without_change {
+            return None;
+        }
+
+        let change_value = inputs_minus_outputs
+            .checked_sub(target_fee_with_change)
+            .filter(|change_value| *change_value >= self.opts.min_drain_value);
+        let (excess, use_change) = match change_value {
             Some(excess) => (excess, true),
             None => {
                 let implied_output_value = self.current_value() - target_fee_without_change;
@@ -166,23 +196,463 @@ impl CoinSelector {
             (base_weight, target_fee_without_change)
         };
 
+        // Like Bitcoin Core: what the inputs cost now compared to in the long run plus either the
+        // cost of creating and later spending the change or the excess we give up as fees.
+        let input_weight = self
+            .selected()
+            .map(|(_, wv)| wv.weight + TXIN_BASE_WEIGHT)
+            .sum::<u32>();
+        let mut waste = self.opts.target_feerate.fee_for_weight(input_weight) as i64
+            - self.opts.long_term_feerate.fee_for_weight(input_weight) as i64;
+        if use_change {
+            waste += (self
+                .opts
+                .target_feerate
+                .fee_for_weight(self.opts.drain_weight)
+                + self
+                    .opts
+                    .long_term_feerate
+                    .fee_for_weight(self.opts.drain_spend_weight)) as i64;
+        } else {
+            waste += excess as i64;
+        }
+
         Some(Selection {
             selected: self.selected.clone(),
             excess,
             use_change,
             total_weight,
             fee,
+            waste,
         })
     }
 }
 
+/// Branch and bound: searches for a selection that doesn't need a change output.
+///
+/// The unselected candidates of `selector` are searched (largest effective value first) for a
+/// subset that pays for the target value and fees while leaving less excess than it would cost to
+/// add the drain output. That cost is the fee of the drain output plus either
+/// [`CoinSelectorOpt::min_drain_value`] or what it costs to spend the drain later at
+/// [`CoinSelectorOpt::long_term_feerate`], whichever is larger. The subset with the least waste is
+/// returned. The search gives up after `max_tries` steps and returns `None` if it
+/// hasn't found one so you can fall back to another algorithm.
+pub fn bnb(selector: &CoinSelector, max_tries: usize) -> Option<Selection> {
+    let opts = &selector.opts;
+    let fee_for = |weight: u32| opts.target_feerate.fee_for_weight(weight) as i64;
+    // Candidates that cost more to spend than they are worth can only make things worse
+    let mut pool = selector
+        .unselected()
+        .into_iter()
+        .map(|index| {
+            let candidate = selector.candidates[index];
+            let effective_value =
+                candidate.value as i64 - fee_for(candidate.weight + TXIN_BASE_WEIGHT);
+            (index, effective_value)
+        })
+        .filter(|(_, effective_value)| *effective_value > 0)
+        .collect::<Vec<_>>();
+    pool.sort_by_key(|(_, effective_value)| core::cmp::Reverse(*effective_value));
+
+    // The effective value the pool has to make up for the selection to be changeless
+    let lower = opts.target_value as i64 + fee_for(selector.current_weight())
+        - selector.current_value() as i64;
+    // Up to here the drain output is either too small to be added or wastes more than the excess
+    let drain_spend_fee = opts
+        .long_term_feerate
+        .fee_for_weight(opts.drain_spend_weight);
+    let upper =
+        lower + fee_for(opts.drain_weight) + opts.min_drain_value.max(drain_spend_fee) as i64;
+
+    let mut selection = selector.clone();
+    // so that finish gives the excess up as fees instead of adding a drain output
+    selection.opts.min_drain_value = u64::MAX;
+    let mut best: Option<Selection> = None;
+    // positions in `pool` that are included in the current branch
+    let mut included: Vec<usize> = Vec::new();
+    let mut value = 0;
+    let mut available: i64 = pool
+        .iter()
+        .map(|(_, effective_value)| effective_value)
+        .sum();
+    let mut next = 0;
+
+    for _ in 0..max_tries {
+        let backtrack = if value + available < lower || value > upper {
+            true
+        } else if value >= lower {
+            if let Some(found) = selection.finish() {
+                let is_better = match &best {
+                    Some(best) => found.waste < best.waste,
+                    None => true,
+                };
+                if is_better {
+                    best = Some(found);
+                }
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // exclude the last included candidate and try the branch without it
+            let last = match included.pop() {
+                Some(last) => last,
+                None => break,
+            };
+            for (_, effective_value) in &pool[last + 1..next] {
+                available += effective_value;
+            }
+            value -= pool[last].1;
+            selection.deselect(pool[last].0);
+            next = last + 1;
+        } else {
+            let (index, effective_value) = pool[next];
+            available -= effective_value;
+            value += effective_value;
+            selection.select(index);
+            included.push(next);
+            next += 1;
+        }
+    }
+
+    best
+}
+
+/// Searches for the selection that costs the least in fees now plus the fee to spend its change
+/// later (at the long term feerate).
+///
+/// Unlike [`bnb`] the selection may have change. Whether to add change is decided by
+/// [`CoinSelector::finish`] for each subset so a subset that would leave less than
+/// [`CoinSelectorOpt::min_drain_value`] is costed with the excess given up as fees. The search gives
+/// up after `max_tries` steps and returns the best selection found so far.
+pub fn lowest_fee(selector: &CoinSelector, max_tries: usize) -> Option<Selection> {
+    let opts = &selector.opts;
+    let fee_for = |weight: u32| opts.target_feerate.fee_for_weight(weight) as i64;
+    let cost = |selection: &Selection| {
+        selection.fee
+            + if selection.use_change {
+                opts.long_term_feerate
+                    .fee_for_weight(opts.drain_spend_weight)
+            } else {
+                selection.excess
+            }
+    };
+    // Candidates that cost more to spend than they are worth can only make things worse
+    let mut pool = selector
+        .unselected()
+        .into_iter()
+        .map(|index| {
+            let candidate = selector.candidates[index];
+            let effective_value =
+                candidate.value as i64 - fee_for(candidate.weight + TXIN_BASE_WEIGHT);
+            (index, effective_value)
+        })
+        .filter(|(_, effective_value)| *effective_value > 0)
+        .collect::<Vec<_>>();
+    pool.sort_by_key(|(_, effective_value)| core::cmp::Reverse(*effective_value));
+
+    // The effective value the pool has to make up for the selection to pay for itself
+    let lower = opts.target_value as i64 + fee_for(selector.current_weight())
+        - selector.current_value() as i64;
+
+    let mut selection = selector.clone();
+    let mut best: Option<(u64, Selection)> = None;
+    // positions in `pool` that are included in the current branch
+    let mut included: Vec<usize> = Vec::new();
+    let mut value = 0;
+    let mut available: i64 = pool
+        .iter()
+        .map(|(_, effective_value)| effective_value)
+        .sum();
+    let mut next = 0;
+
+    for _ in 0..max_tries {
+        // adding inputs never makes the fee go down
+        let too_costly = match &best {
+            Some((best_cost, _)) => fee_for(selection.current_weight()) >= *best_cost as i64,
+            None => false,
+        };
+        let backtrack = if value + available < lower || too_costly {
+            true
+        } else if let Some(found) = selection.finish() {
+            let found_cost = cost(&found);
+            let is_better = match &best {
+                Some((best_cost, _)) => found_cost < *best_cost,
+                None => true,
+            };
+            // with change more inputs only add fees but a changeless selection with a lot of
+            // excess may be cheaper with another input and change
+            let use_change = found.use_change;
+            if is_better {
+                best = Some((found_cost, found));
+            }
+            use_change || next == pool.len()
+        } else {
+            next == pool.len()
+        };
+
+        if backtrack {
+            // exclude the last included candidate and try the branch without it
+            let last = match included.pop() {
+                Some(last) => last,
+                None => break,
+            };
+            for (_, effective_value) in &pool[last + 1..next] {
+                available += effective_value;
+            }
+            value -= pool[last].1;
+            selection.deselect(pool[last].0);
+            next = last + 1;
+        } else {
+            let (index, effective_value) = pool[next];
+            available -= effective_value;
+            value += effective_value;
+            selection.select(index);
+            included.push(next);
+            next += 1;
+        }
+    }
+
+    best.map(|(_, selection)| selection)
+}
+
+/// Selects the unselected candidates from the largest value to the smallest until finished.
+pub fn largest_first(selector: &CoinSelector) -> Option<Selection> {
+    let mut order = selector.unselected();
+    order.sort_by_key(|index| core::cmp::Reverse(selector.candidates[*index].value));
+    select_in_order(selector, order)
+}
+
+/// Selects the unselected candidates from the smallest value to the largest until finished.
+pub fn smallest_first(selector: &CoinSelector) -> Option<Selection> {
+    let mut order = selector.unselected();
+    order.sort_by_key(|index| selector.candidates[*index].value);
+    select_in_order(selector, order)
+}
+
+/// Single random draw: selects the unselected candidates in a random order until finished.
+///
+/// `rng` is called for random numbers so you can pass in whichever source of randomness you
+/// have.
+pub fn single_random_draw(
+    selector: &CoinSelector,
+    rng: &mut impl FnMut() -> u64,
+) -> Option<Selection> {
+    let mut order = selector.unselected();
+    shuffle(&mut order, rng);
+    select_in_order(selector, order)
+}
+
+/// Knapsack selection like Bitcoin Core's (before it had branch and bound).
+///
+/// If no candidate or set of smaller candidates matches what is needed exactly it randomly looks
+/// for the set of smaller candidates that leaves the least excess over `iterations` tries. It
+/// first looks for a changeless set and then for one that leaves [`CoinSelectorOpt::min_drain_value`]
+/// for change. The single candidate large enough to pay for change on its own is used instead if
+/// the set would be larger. `rng` is called for random numbers.
+pub fn knapsack(
+    selector: &CoinSelector,
+    rng: &mut impl FnMut() -> u64,
+    iterations: usize,
+) -> Option<Selection> {
+    let opts = &selector.opts;
+    let fee_for = |weight: u32| opts.target_feerate.fee_for_weight(weight) as i64;
+    let mut pool = selector
+        .unselected()
+        .into_iter()
+        .map(|index| {
+            let candidate = selector.candidates[index];
+            let effective_value =
+                candidate.value as i64 - fee_for(candidate.weight + TXIN_BASE_WEIGHT);
+            (index, effective_value)
+        })
+        .filter(|(_, effective_value)| *effective_value > 0)
+        .collect::<Vec<_>>();
+    // shuffle first so candidates with the same value are chosen at random
+    shuffle(&mut pool, rng);
+    pool.sort_by_key(|(_, effective_value)| core::cmp::Reverse(*effective_value));
+
+    // The effective value needed without and with change
+    let target = opts.target_value as i64 + fee_for(selector.current_weight())
+        - selector.current_value() as i64;
+    let target_with_change = target + fee_for(opts.drain_weight) + opts.min_drain_value as i64;
+
+    if target <= 0 {
+        return select_then_in_order(selector, &[], &pool);
+    }
+
+    let mut lower = Vec::new();
+    let mut lowest_larger = None;
+    for &(index, effective_value) in &pool {
+        if effective_value == target {
+            return select_then_in_order(selector, &[index], &pool);
+        } else if effective_value < target_with_change {
+            lower.push((index, effective_value));
+        } else {
+            // the pool is sorted so the last one is the smallest
+            lowest_larger = Some((index, effective_value));
+        }
+    }
+
+    let lower_total = lower
+        .iter()
+        .map(|(_, effective_value)| effective_value)
+        .sum::<i64>();
+    if lower_total == target {
+        let indexes = lower.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        return select_then_in_order(selector, &indexes, &pool);
+    }
+    if lower_total < target {
+        return select_then_in_order(selector, &[lowest_larger?.0], &pool);
+    }
+
+    let (mut best, mut best_total) = approximate_best_subset(&lower, target, rng, iterations);
+    if best_total != target && lower_total >= target_with_change {
+        (best, best_total) = approximate_best_subset(&lower, target_with_change, rng, iterations);
+    }
+    match lowest_larger {
+        Some((index, effective_value))
+            if (best_total != target && best_total < target_with_change)
+                || effective_value <= best_total =>
+        {
+            select_then_in_order(selector, &[index], &pool)
+        }
+        _ => select_then_in_order(selector, &best, &pool),
+    }
+}
+
+/// Randomly includes and excludes `candidates` to find the set whose total effective value is
+/// the smallest at or above `target`. Returns the indexes of the set and its total.
+fn approximate_best_subset(
+    candidates: &[(usize, i64)],
+    target: i64,
+    rng: &mut impl FnMut() -> u64,
+    iterations: usize,
+) -> (Vec<usize>, i64) {
+    let mut best = vec![true; candidates.len()];
+    let mut best_total = candidates.iter().map(|(_, value)| value).sum::<i64>();
+
+    for _ in 0..iterations {
+        if best_total == target {
+            break;
+        }
+        let mut included = vec![false; candidates.len()];
+        let mut total = 0;
+        let mut reached_target = false;
+        for pass in 0..2 {
+            if reached_target {
+                break;
+            }
+            // include about half at random then everything else until we reach the target
+            for (i, (_, value)) in candidates.iter().enumerate() {
+                let include = match pass {
+                    0 => rng() % 2 == 1,
+                    _ => !included[i],
+                };
+                if include {
+                    total += value;
+                    included[i] = true;
+                    if total >= target {
+                        reached_target = true;
+                        if total < best_total {
+                            best_total = total;
+                            best = included.clone();
+                        }
+                        total -= value;
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+    }
+
+    let indexes = candidates
+        .iter()
+        .zip(best)
+        .filter(|(_, included)| *included)
+        .map(|((index, _), _)| *index)
+        .collect();
+    (indexes, best_total)
+}
+
+/// Selects `indexes` and then the rest of `pool` in order until finished since rounding the fee
+/// of each input separately can leave the selection a few sats short.
+fn select_then_in_order(
+    selector: &CoinSelector,
+    indexes: &[usize],
+    pool: &[(usize, i64)],
+) -> Option<Selection> {
+    let mut selector = selector.clone();
+    for index in indexes {
+        selector.select(*index);
+    }
+    let rest = pool
+        .iter()
+        .map(|(index, _)| *index)
+        .filter(|index| !indexes.contains(index))
+        .collect();
+    select_in_order(&selector, rest)
+}
+
+/// Fisher-Yates shuffle with numbers from `rng`.
+fn shuffle<T>(items: &mut [T], rng: &mut impl FnMut() -> u64) {
+    for i in (1..items.len()).rev() {
+        let j = (rng() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+fn select_in_order(selector: &CoinSelector, order: Vec<usize>) -> Option<Selection> {
+    let mut selector = selector.clone();
+    for index in order {
+        if let Some(selection) = selector.finish() {
+            return Some(selection);
+        }
+        selector.select(index);
+    }
+    selector.finish()
+}
+
+/// A coin selection algorithm that can be passed to [`lowest_waste`].
+pub type SelectionStrategy = dyn Fn(&CoinSelector) -> Option<Selection>;
+
+/// Runs each of `strategies` (e.g. [`bnb`], [`largest_first`] and [`smallest_first`]) on
+/// `selector` and returns the selection with the least waste.
+pub fn lowest_waste(
+    selector: &CoinSelector,
+    strategies: &[&SelectionStrategy],
+) -> Option<Selection> {
+    let mut best: Option<Selection> = None;
+    for strategy in strategies {
+        if let Some(selection) = strategy(selector) {
+            let is_better = match &best {
+                Some(best) => selection.waste < best.waste,
+                None => true,
+            };
+            if is_better {
+                best = Some(selection);
+            }
+        }
+    }
+    best
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct Selection {
     pub selected: BTreeSet<usize>,
     pub excess: u64,
     pub fee: u64,
     pub use_change: bool,
     pub total_weight: u32,
+    /// How much worse this selection is than an ideal one in sats. See [`CoinSelector::finish`].
+    pub waste: i64,
 }
 
 impl Selection {
@@ -193,3 +663,159 @@ impl Selection {
         self.selected.iter().map(|i| &candidates[*i])
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// xorshift so the tests are deterministic
+    fn rng(mut state: u64) -> impl FnMut() -> u64 {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    fn selector(target_value: u64, long_term_sat_per_wu: u64) -> CoinSelector {
+        let candidates = [12_000, 7_000, 6_000, 4_000, 3_564]
+            .iter()
+            .map(|value| WeightedValue {
+                value: *value,
+                weight: 0,
+            })
+            .collect();
+        CoinSelector::new(
+            candidates,
+            CoinSelectorOpt {
+                target_value,
+                target_feerate: FeeRate::from_sat_per_kwu(1_000),
+                long_term_feerate: FeeRate::from_sat_per_kwu(long_term_sat_per_wu * 1_000),
+                drain_spend_weight: 100,
+                ..CoinSelectorOpt::from_weights(200, 100)
+            },
+        )
+    }
+
+    #[test]
+    fn bnb_finds_changeless_selection() {
+        let selection = bnb(&selector(10_000, 1), 100_000).unwrap();
+        assert_eq!(selection.selected, [1, 4].into_iter().collect());
+        assert!(!selection.use_change);
+        assert_eq!(selection.excess, 36);
+        assert_eq!(selection.waste, 36);
+
+        // nothing lands between the target and the target plus the cost of change
+        assert!(bnb(&selector(10_500, 1), 100_000).is_none());
+        assert!(selector(10_500, 1).select_until_finished().is_some());
+        // gives up before finding it
+        assert!(bnb(&selector(10_000, 1), 2).is_none());
+    }
+
+    #[test]
+    fn bnb_leaves_out_drain_below_its_cost() {
+        // 12000 leaves 500 of excess, more than the 100 the drain output costs to add
+        let mut below_min_drain = selector(11_136, 1);
+        below_min_drain.opts.min_drain_value = 1_000;
+        let selection = bnb(&below_min_drain, 100_000).unwrap();
+        assert_eq!(selection.selected, [0].into_iter().collect());
+        assert!(!selection.use_change);
+        assert_eq!(selection.excess, 500);
+
+        // spending the drain later would cost 400 on top of that
+        let selection = bnb(&selector(11_136, 4), 100_000).unwrap();
+        assert_eq!(selection.selected, [0].into_iter().collect());
+        assert!(!selection.use_change);
+        assert_eq!(selection.waste, 164 * -3 + 500);
+
+        // 6836 + 5836 leaves 1336 which is worth paying for change
+        assert!(bnb(&selector(11_136, 1), 100_000).is_none());
+    }
+
+    #[test]
+    fn lowest_waste_depends_on_long_term_feerate() {
+        let strategies: [&SelectionStrategy; 3] = [
+            &|selector| bnb(selector, 100_000),
+            &largest_first,
+            &smallest_first,
+        ];
+
+        // the changeless selection wastes only its excess
+        let expect_no_rise = selector(10_000, 1);
+        let change = largest_first(&expect_no_rise).unwrap();
+        assert!(change.use_change);
+        assert_eq!(change.waste, 100 + 100);
+        let selection = lowest_waste(&expect_no_rise, &strategies).unwrap();
+        assert_eq!(selection.selected, [1, 4].into_iter().collect());
+
+        // when fees are expected to go up spending the three smallest saves the most
+        let expect_rise = selector(10_000, 4);
+        let selection = lowest_waste(&expect_rise, &strategies).unwrap();
+        assert_eq!(selection.selected, [2, 3, 4].into_iter().collect());
+        assert_eq!(selection.waste, 3 * 164 * -3 + 100 + 400);
+    }
+
+    #[test]
+    fn lowest_fee_decides_change_with_inputs() {
+        // a single input with change costs 464 now plus 100 to spend the change later
+        let selection = lowest_fee(&selector(10_500, 1), 100_000).unwrap();
+        assert_eq!(selection.selected, [0].into_iter().collect());
+        assert!(selection.use_change);
+        assert_eq!(selection.excess, 1_036);
+
+        // that change is too small now so adding an input is cheaper than giving it up as fees
+        let mut selector = selector(10_500, 1);
+        selector.opts.min_drain_value = 2_000;
+        let mut in_order = selector.clone();
+        let dropped = in_order.select_until_finished().unwrap();
+        assert!(!dropped.use_change);
+        assert_eq!(dropped.excess, 1_136);
+
+        let selection = lowest_fee(&selector, 100_000).unwrap();
+        assert_eq!(selection.selected.len(), 2);
+        assert!(selection.use_change);
+        assert_eq!(selection.fee, 628);
+    }
+
+    #[test]
+    fn single_random_draw_depends_on_rng() {
+        let selector = selector(10_000, 1);
+        let selections = (1..20)
+            .map(|seed| single_random_draw(&selector, &mut rng(seed)).unwrap())
+            .collect::<Vec<_>>();
+        for selection in &selections {
+            let value = selection
+                .apply_selection(selector.candidates())
+                .map(|wv| wv.value)
+                .sum::<u64>();
+            assert_eq!(value, 10_000 + selection.fee + selection.excess);
+        }
+        let distinct = selections
+            .iter()
+            .map(|selection| selection.selected.clone())
+            .collect::<BTreeSet<_>>();
+        assert!(distinct.len() > 1);
+        assert_eq!(
+            single_random_draw(&selector, &mut rng(1)).unwrap().selected,
+            selections[0].selected
+        );
+    }
+
+    #[test]
+    fn knapsack_finds_smallest_subset_over_target() {
+        // 6836 + 3836 is the smallest effective value that pays for change
+        let selection = knapsack(&selector(10_000, 1), &mut rng(1), 1_000).unwrap();
+        assert_eq!(selection.selected, [1, 3].into_iter().collect());
+        assert!(selection.use_change);
+        assert_eq!(selection.excess, 372);
+
+        // now 12000 isn't large enough to leave the minimum change on its own
+        let mut selector = selector(10_000, 1);
+        selector.opts.min_drain_value = 2_000;
+        let selection = knapsack(&selector, &mut rng(1), 1_000).unwrap();
+        assert_eq!(selection.selected, [1, 2].into_iter().collect());
+        assert!(selection.use_change);
+        assert_eq!(selection.excess, 2_372);
+    }
+}
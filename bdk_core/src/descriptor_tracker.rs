@@ -1,500 +1,468 @@
-use crate::{BlockTime, CheckPoint, HashMap, HashSet, PrevOuts};
-use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use crate::{
+    spk_tracker::{SpkTracker, UpdateCheck},
+    Amount, Balance, BlockTime, CheckPoint, FeeRate, HashSet, PrevOuts, SignedAmount,
+    COINBASE_MATURITY,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
 use bitcoin::{
     psbt::{self, PartiallySignedTransaction as Psbt},
     secp256k1::{Secp256k1, VerifyOnly},
     util::address::WitnessVersion,
     BlockHash, OutPoint, Script, Transaction, TxIn, TxOut, Txid,
 };
-use core::ops::RangeInclusive;
 use miniscript::{
-    descriptor::DerivedDescriptorKey, psbt::PsbtInputExt, Descriptor, DescriptorPublicKey,
+    descriptor::{ConversionError, DerivedDescriptorKey},
+    psbt::PsbtInputExt,
+    Descriptor, DescriptorPublicKey,
 };
 
 #[derive(Clone, Debug)]
 pub struct DescriptorTracker {
-    /// The descriptor we are tracking
-    descriptor: Descriptor<DescriptorPublicKey>,
-    /// Which txids are included in which checkpoints
-    checkpointed_txs: BTreeMap<u32, (BlockHash, HashSet<Txid>)>,
-    /// The txouts owned by this tracker
-    txouts: BTreeMap<OutPoint, TxOutData>,
-    /// The unspent txouts
-    unspent: HashSet<OutPoint>,
-    /// The ordered script pubkeys that have been derived from the descriptor.
-    scripts: Vec<Script>,
-    /// A reverse lookup from script to derivation index
-    script_indexes: HashMap<Script, u32>,
-    /// A lookup from script pubkey to outpoint
-    script_txouts: BTreeMap<u32, HashSet<OutPoint>>,
-    /// The next derivation index the tracker should used if asked for a "new" script pubkey.
-    next_derivation_index: u32,
-    /// Map from txid to metadata
-    txs: HashMap<Txid, AugmentedTx>,
-    /// Index of transactions that are in the mempool
-    mempool: HashSet<Txid>,
-    // TODO change to blocktime + height
-    latest_blockheight: Option<u32>,
+    /// The descriptor we are tracking and the script pubkeys derived from it
+    derivation: DerivedScripts,
+    /// The txs, txouts and checkpoints related to the derived script pubkeys
+    graph: SpkTracker<u32>,
     secp: Secp256k1<VerifyOnly>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum UpdateResult {
-    Ok,
+pub enum UpdateResult<S = DerivationChanges> {
+    /// The update was applied which resulted in the changes in the [`ChangeSet`]
+    Ok(ChangeSet<S>),
     Stale,
 }
 
-impl DescriptorTracker {
-    pub fn new(descriptor: Descriptor<DescriptorPublicKey>) -> Self {
-        Self {
-            descriptor,
-            checkpointed_txs: Default::default(),
-            secp: Secp256k1::verification_only(),
-            txouts: Default::default(),
-            unspent: Default::default(),
-            scripts: Default::default(),
-            script_indexes: Default::default(),
-            script_txouts: Default::default(),
-            txs: Default::default(),
-            next_derivation_index: 0,
-            mempool: Default::default(),
-            latest_blockheight: Default::default(),
+/// An update that cannot be applied to a [`DescriptorTracker`] since it is inconsistent with
+/// itself or with the data in the tracker.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateError {
+    /// A tx was confirmed at a different height (or not at all) to what the tracker already has or
+    /// to another entry for it in the update
+    InconsistentConfirmation {
+        txid: Txid,
+        existing_height: u32,
+        update_height: Option<u32>,
+    },
+    /// A tx was confirmed above the update's new tip
+    TxConfirmedAboveTip {
+        txid: Txid,
+        confirmation_height: u32,
+        tip_height: u32,
+    },
+    /// The update invalidates a checkpoint the tracker doesn't have
+    MissingCheckpoint { height: u32 },
+}
+
+impl core::fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UpdateError::InconsistentConfirmation {
+                txid,
+                existing_height,
+                update_height,
+            } => match update_height {
+                Some(update_height) => write!(
+                    f,
+                    "tx {} confirmed at {} is confirmed at {} in the update",
+                    txid, existing_height, update_height
+                ),
+                None => write!(
+                    f,
+                    "tx {} confirmed at {} is unconfirmed in the update",
+                    txid, existing_height
+                ),
+            },
+            UpdateError::TxConfirmedAboveTip {
+                txid,
+                confirmation_height,
+                tip_height,
+            } => write!(
+                f,
+                "tx {} is confirmed at {} which is above the update tip {}",
+                txid, confirmation_height, tip_height
+            ),
+            UpdateError::MissingCheckpoint { height } => {
+                write!(f, "there is no checkpoint at {} to invalidate", height)
+            }
         }
     }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UpdateError {}
+
+/// A descriptor that can't be tracked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DescriptorError {
+    /// The descriptor has a hardened wildcard (`/*'`) so its script pubkeys can only be derived
+    /// with the private key
+    HardenedWildcard,
+    /// The descriptor has a hardened step after an extended public key so its script pubkeys can
+    /// only be derived with the private key
+    HardenedDerivation,
+    /// The descriptor can't be satisfied so txouts paying to it can't be spent
+    Unsatisfiable,
+}
+
+impl core::fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DescriptorError::HardenedWildcard => write!(
+                f,
+                "the descriptor has a hardened wildcard which can only be derived with the private key"
+            ),
+            DescriptorError::HardenedDerivation => write!(
+                f,
+                "the descriptor has a hardened step after an xpub which can only be derived with the private key"
+            ),
+            DescriptorError::Unsatisfiable => write!(f, "the descriptor can't be satisfied"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DescriptorError {}
+
+impl DescriptorTracker {
+    /// Creates a tracker for `descriptor`.
+    ///
+    /// Fails if the descriptor's script pubkeys can't be derived from its public keys (i.e. it has
+    /// hardened derivation steps) or it can't be satisfied.
+    pub fn new(descriptor: Descriptor<DescriptorPublicKey>) -> Result<Self, DescriptorError> {
+        let secp = Secp256k1::verification_only();
+        Ok(Self {
+            derivation: DerivedScripts::new(descriptor, &secp)?,
+            graph: Default::default(),
+            secp,
+        })
+    }
 
     pub fn latest_blockheight(&self) -> Option<u32> {
-        self.latest_blockheight
+        self.graph.latest_blockheight()
     }
 
     pub fn descriptor(&self) -> &Descriptor<DescriptorPublicKey> {
-        &self.descriptor
+        self.derivation.descriptor()
     }
 
     pub fn next_derivation_index(&self) -> u32 {
-        self.next_derivation_index
+        self.derivation.next_derivation_index()
     }
 
     pub fn latest_checkpoint(&self) -> Option<CheckPoint> {
-        self.checkpointed_txs
-            .iter()
-            .last()
-            .map(|(height, (hash, _))| CheckPoint {
-                height: *height,
-                hash: *hash,
-            })
+        self.graph.latest_checkpoint()
     }
 
     pub fn checkpoint_at(&self, height: u32) -> Option<CheckPoint> {
-        self.checkpointed_txs
-            .get(&height)
-            .map(|(hash, _)| CheckPoint {
-                height,
-                hash: *hash,
-            })
+        self.graph.checkpoint_at(height)
     }
 
-    pub fn iter_checkpoints(&self) -> impl Iterator<Item = (CheckPoint, &HashSet<Txid>)> {
-        self.checkpointed_txs.iter().map(|(height, (hash, txs))| {
-            (
-                CheckPoint {
-                    height: *height,
-                    hash: *hash,
-                },
-                txs,
-            )
-        })
+    /// A commitment to all the txids confirmed up to and including the checkpoint at `height`.
+    ///
+    /// The commitment is the XOR of `sha256(txid)` for each txid so it doesn't depend on the order
+    /// the txs were found in. Two trackers with a checkpoint at the same height will only have the
+    /// same commitment for it if they agree on which txs are confirmed up to that point. Since it
+    /// covers everything below the checkpoint you can binary search over the checkpoints to find the
+    /// first one where two trackers diverge.
+    pub fn checkpoint_commitment(&self, height: u32) -> Option<[u8; 32]> {
+        self.graph.checkpoint_commitment(height)
     }
 
-    fn remove_tx(&mut self, txid: Txid) {
-        let txouts_to_remove = self
-            .txouts
-            .range(RangeInclusive::new(
-                OutPoint { txid, vout: 0 },
-                OutPoint {
-                    txid,
-                    vout: u32::MAX,
-                },
-            ))
-            .map(|(k, _)| *k)
-            .collect::<Vec<_>>();
-
-        for txout_to_remove in txouts_to_remove {
-            if let Some(txout) = self.txouts.remove(&txout_to_remove) {
-                self.script_txouts
-                    .get_mut(&txout.index)
-                    .expect("guaranteed to exist")
-                    .remove(&txout_to_remove);
-            }
-        }
+    pub fn iter_checkpoints(&self) -> impl Iterator<Item = (CheckPoint, &HashSet<Txid>)> {
+        self.graph.iter_checkpoints()
+    }
 
-        if let Some(aug_tx) = self.txs.remove(&txid) {
-            for (i, input) in aug_tx.tx.input.iter().enumerate() {
-                if let Some(txout) = self.txouts.get(&input.previous_output) {
-                    debug_assert_eq!(
-                        txout.spent_by,
-                        Some((i as u32, aug_tx.tx.txid())),
-                        "tx being removed was not the child of its parent in the database."
-                    );
-                    // this previous spent output is now unspent
-                    self.unspent.insert(input.previous_output);
-                }
-            }
-        }
+    /// Checkpoints to find where the tracker's view of the chain forks from the current one. The
+    /// first ten are one block apart and after that the gap in height doubles each time. Where
+    /// there is no checkpoint at a height the nearest one below it is used. The lowest checkpoint
+    /// is always included.
+    ///
+    /// Pass it to a blockchain client so it can set the [`Update`]'s `base_tip` to the highest of
+    /// these that is still in the chain and `invalidate` the ones above it.
+    pub fn block_locator(&self) -> Vec<CheckPoint> {
+        self.graph.block_locator()
+    }
 
-        self.mempool.remove(&txid);
+    /// Thins out old checkpoints so a long running tracker doesn't keep one for every update that
+    /// confirmed something. The latest `keep_recent` checkpoints are kept and below those only the
+    /// highest checkpoint in each range of heights that is twice as large as the one above it. The
+    /// txs of a removed checkpoint are moved into the next newer checkpoint that is kept.
+    pub fn prune_checkpoints(&mut self, keep_recent: usize) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        self.graph.prune_checkpoints(keep_recent, &mut changeset);
+        changeset
     }
 
-    fn add_tx(&mut self, inputs: PrevOuts, tx: Transaction, confirmation_time: Option<BlockTime>) {
-        let txid = tx.txid();
-        // compare to potentially existing tx in the database
-        if let Some(existing) = self.txs.get_mut(&txid) {
-            match (existing.confirmation_time, confirmation_time) {
-                (Some(existing_time), Some(new_time)) => {
-                    if existing_time != new_time {
-                        todo!("return an error since this cannot change");
-                    }
-                }
-                (Some(_), None) => todo!("return an error since this doesn't make sense"),
-                (None, None) => {
-                    return;
-                }
-                (None, Some(new_time)) => {
-                    existing.confirmation_time = Some(new_time);
-                    return;
-                }
-            }
-        }
-        let mut inputs_sum: u64 = 0;
-        let mut outputs_sum: u64 = 0;
+    pub fn apply_update(&mut self, update: Update) -> Result<UpdateResult, UpdateError> {
+        let invalidate_from = match self.graph.check_update(&update)? {
+            UpdateCheck::Stale => return Ok(UpdateResult::Stale),
+            UpdateCheck::Consistent { invalidate_from } => invalidate_from,
+        };
 
-        match inputs {
-            PrevOuts::Coinbase => {
-                debug_assert_eq!(tx.input.len(), 1);
-                debug_assert!(tx.input[0].previous_output.is_null());
-            }
-            PrevOuts::Spend(txouts) => {
-                for txout in txouts.iter() {
-                    inputs_sum += txout.value;
-                }
-            }
+        // Nothing can go wrong from here on so we can start changing things.
+        let mut changeset: ChangeSet = ChangeSet::default();
+        // It's possible that we find a script derived at a higher index than what we have given
+        // out in the case where another system is deriving from the same descriptor. The txs may
+        // also pay to lookahead scripts we haven't given out. Revealing those stores more
+        // lookahead scripts so we keep going until the txs don't pay to any unrevealed ones.
+        let mut reveal_to = update
+            .last_active_index
+            .or_else(|| self.unrevealed_index_paid_by(&update));
+        while let Some(index) = reveal_to {
+            let graph = &mut self.graph;
+            let changes = self
+                .derivation
+                .reveal_to(index, &self.secp, |index, script| {
+                    graph.add_script(script, index)
+                });
+            changeset.scripts.append(changes);
+            reveal_to = self.unrevealed_index_paid_by(&update);
         }
 
-        for (i, out) in tx.output.iter().enumerate() {
-            outputs_sum += out.value;
-            if let Some(index) = self.index_of_stored_script(&out.script_pubkey) {
-                let outpoint = OutPoint {
-                    txid,
-                    vout: i as u32,
-                };
+        self.graph
+            .apply_checked_update(update, invalidate_from, &mut changeset);
 
-                // TODO: what if it is spent by a transaction in our state already? i.e. it's a old
-                // tx that we've just found out about that has already been spent.
-                // To fix this we could:
-                // 1. Create an index of previous_output -> tx that is spending it
-                // 2. Simply go through all txs that are "newer" than this one (confirmed after or in mempool)
-                // Since you will ususally only have newish transactions here (2) seems fine.
-                self.txouts.insert(
-                    outpoint,
-                    TxOutData {
-                        value: out.value,
-                        spent_by: None,
-                        index,
-                    },
-                );
+        Ok(UpdateResult::Ok(changeset))
+    }
 
-                self.unspent.insert(outpoint);
+    /// The highest index of a stored script pubkey at or past the next derivation index that a tx
+    /// in `update` pays to.
+    fn unrevealed_index_paid_by(&self, update: &Update) -> Option<u32> {
+        let next_derivation_index = self.derivation.next_derivation_index();
+        self.graph
+            .indexes_paid_by(update)
+            .filter(|index| **index >= next_derivation_index)
+            .max()
+            .cloned()
+    }
 
-                let txos_for_script = self.script_txouts.entry(index).or_default();
-                txos_for_script.insert(outpoint);
-            }
-        }
+    pub fn lookahead(&self) -> u32 {
+        self.derivation.lookahead()
+    }
 
-        match confirmation_time {
-            Some(confirmation_time) => {
-                // Find the first checkpoint above or equal to the tx's height
-                let checkpoint_height: Option<u32> = self
-                    .checkpointed_txs
-                    .range(confirmation_time.height..)
-                    .next()
-                    .map(|(height, _)| *height);
-
-                match checkpoint_height {
-                    Some(checkpoint_height) => {
-                        // Rebase onto the checkpoint, removing all checkpoints after after
-                        // and including the target. We do this to keep the rule: Never add new txs
-                        // to a checkpoint once you've added it into checkpointed_txs. But we *can*
-                        // remove checkpoints and move the txids from older ones to the tip.
-                        //
-                        // NOTE: the usual case is that checkpoint_height == tip_height in which
-                        // case the following will just insert the new txid into the tip.
-                        let removed = self.checkpointed_txs.split_off(&checkpoint_height);
-                        let (tip_height, (tip_hash, _)) = removed.iter().rev().next().unwrap();
-                        let txids = removed
-                            .values()
-                            .map(|(_, txs)| txs.iter().cloned())
-                            .flatten()
-                            .chain(core::iter::once(txid))
-                            .collect();
-                        self.checkpointed_txs
-                            .insert(*tip_height, (*tip_hash, txids));
-                        self.mempool.remove(&txid);
-                    }
-                    None => {
-                        unreachable!(
-                            "the caller must have checked that no txs are outside of range"
-                        )
-                    }
-                }
-            }
-            None => {
-                self.mempool.insert(txid);
-            }
+    /// Sets how many script pubkeys past the next derivation index the tracker keeps stored.
+    ///
+    /// Txs paying to these are found even though the tracker didn't hand them out (e.g. because
+    /// another wallet derives from the same descriptor). Finding one moves the next derivation
+    /// index past it.
+    pub fn set_lookahead(&mut self, lookahead: u32) -> ChangeSet {
+        self.derivation.set_lookahead(lookahead);
+        let graph = &mut self.graph;
+        let last_stored_index = self
+            .derivation
+            .store_lookahead(&self.secp, |index, script| graph.add_script(script, index));
+        ChangeSet {
+            scripts: DerivationChanges {
+                last_stored_index,
+                lookahead: Some(lookahead),
+                ..Default::default()
+            },
+            ..Default::default()
         }
+    }
 
-        // we need to saturating sub since we want coinbase txs to map to 0 fee and
-        // this subtraction will be negative for coinbase txs.
-        let fee = inputs_sum.saturating_sub(outputs_sum);
-        let feerate = fee as f32 / (tx.weight() as f32 / 4.0).ceil();
-
-        self.txs.insert(
-            txid,
-            AugmentedTx {
-                tx,
-                fee,
-                feerate,
-                confirmation_time,
-            },
-        );
+    /// Applies a [`ChangeSet`] that was produced by a tracker with the same descriptor when it was
+    /// in the same state as this one.
+    ///
+    /// This is how you restore a tracker from persistent storage: store the changesets as you make
+    /// changes and then apply them in the same order to a new tracker.
+    pub fn apply_changeset(&mut self, changeset: ChangeSet) {
+        let graph = &mut self.graph;
+        self.derivation
+            .apply_changes(changeset.scripts, &self.secp, |index, script| {
+                graph.add_script(script, index)
+            });
+        self.graph.apply_changeset(changeset);
     }
 
-    fn invalidate_checkpoint(&mut self, height: u32) {
-        let removed = self.checkpointed_txs.split_off(&height);
-        let txs_to_remove = removed.values().map(|(_, txs)| txs).flatten();
-        for tx_to_remove in txs_to_remove {
-            self.remove_tx(*tx_to_remove);
-        }
-    }
-
-    pub fn apply_update(&mut self, update: Update) -> UpdateResult {
-        if let Some(last_active_index) = update.last_active_index {
-            // It's possible that we find a script derived at a higher index than what we have given
-            // out in the case where another system is deriving from the same descriptor.
-            self.next_derivation_index = (last_active_index + 1).max(self.next_derivation_index);
-            self.store_scripts(last_active_index);
-        }
-
-        // look for invalidated and check that start tip is the one before it.
-        match update.invalidate {
-            Some(checkpoint_reset) => match self.checkpointed_txs.get(&checkpoint_reset.height) {
-                Some((existing_hash, _)) => {
-                    if *existing_hash != checkpoint_reset.hash {
-                        if self
-                            .checkpointed_txs
-                            .range(..checkpoint_reset.height)
-                            .last()
-                            .map(|(height, (hash, _))| CheckPoint {
-                                height: *height,
-                                hash: *hash,
-                            })
-                            == update.base_tip
-                        {
-                            self.invalidate_checkpoint(checkpoint_reset.height);
-                        } else {
-                            return UpdateResult::Stale;
-                        }
-                    } else {
-                        return UpdateResult::Stale;
-                    }
-                }
-                None => return UpdateResult::Stale,
-            },
-            None => {
-                if update.base_tip != self.latest_checkpoint() {
-                    return UpdateResult::Stale;
-                }
-            }
+    /// Returns a [`ChangeSet`] which recreates the current state of the tracker when applied to a
+    /// new tracker with the same descriptor.
+    ///
+    /// This is useful to compact a history of changesets into a single one.
+    pub fn full_changeset(&self) -> ChangeSet {
+        ChangeSet {
+            scripts: self.derivation.full_changes(),
+            ..self.graph.full_changeset()
         }
+    }
 
-        if update.mempool_is_total_set {
-            // This update will include everything in the mempool that is relevent to the tracker so
-            // we clear everything.
-            self.clear_mempool();
-        }
+    pub fn clear_mempool(&mut self) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        self.graph.clear_mempool(&mut changeset);
+        changeset
+    }
 
-        // Insert a new empty checkpoint at the update height
-        self.checkpointed_txs
-            .entry(update.new_tip.height)
-            .or_insert_with(|| (update.new_tip.hash, Default::default()));
+    /// Removes the unconfirmed txs that were last seen before the unix timestamp `timestamp`. Use
+    /// this to expire txs when your updates don't have `mempool_is_total_set`.
+    pub fn prune_mempool_older_than(&mut self, timestamp: u64) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        self.graph
+            .prune_mempool_older_than(timestamp, &mut changeset);
+        changeset
+    }
 
-        for (vouts, tx, confirmation_time) in update.transactions {
-            self.add_tx(vouts, tx, confirmation_time);
-        }
+    /// Forgets the txs that were evicted by a tx that now has at least `confirmations`
+    /// confirmations. Call this now and then so that the evicted txs don't pile up forever.
+    pub fn prune_evicted(&mut self, confirmations: u32) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        self.graph.prune_evicted(confirmations, &mut changeset);
+        changeset
+    }
 
-        let (_, tip_txids) = self.checkpointed_txs.values().rev().next().unwrap();
+    pub fn disconnect_block(&mut self, block_height: u32, block_header: BlockHash) -> ChangeSet {
+        let mut changeset = ChangeSet::default();
+        self.graph
+            .disconnect_block(block_height, block_header, &mut changeset);
+        changeset
+    }
 
-        let spent_inputs = tip_txids
-            .iter()
-            .chain(&self.mempool)
-            .map(|txid| {
-                let aug_tx = self.txs.get(txid).expect("existence guaranteed");
-                aug_tx
-                    .tx
-                    .input
-                    .iter()
-                    .enumerate()
-                    .map(|(i, input)| (input.previous_output, i, *txid))
-            })
-            .flatten();
+    pub fn iter_tx(&self) -> impl Iterator<Item = (Txid, &AugmentedTx)> {
+        self.graph.iter_tx()
+    }
 
-        for (outpoint, in_index, txid) in spent_inputs {
-            if let Some(txout) = self.txouts.get_mut(&outpoint) {
-                if let Some(spent_by) = txout.spent_by {
-                    // TODO: reslove mempool conflicts so this doesn't happen
-                    debug_assert_eq!(spent_by, (in_index as u32, txid));
-                }
-                txout.spent_by = Some((in_index as u32, txid));
-                self.unspent.remove(&outpoint);
-            }
+    /// A page of at most `limit` entries of the tx history starting at `offset`, most recent
+    /// first.
+    ///
+    /// Only the txs of the checkpoints that overlap the page are sorted so the cost of a page
+    /// doesn't grow with the number of txs in older checkpoints.
+    pub fn history(&self, offset: usize, limit: usize) -> Vec<HistoryEntry> {
+        self.graph.history(offset, limit)
+    }
 
-            // TODO: What if the txo is ours but we just haven't got it in self.txouts perhaps
-            // because we failed to store enough scripts to find it earlier. We should check this
-            // somewhere (where it's possible) and
-        }
+    pub fn iter_unspent(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
+        self.graph.iter_unspent().map(|(_, txout)| txout)
+    }
 
-        if tip_txids.is_empty() {
-            // the new checkpoint we inserted ends up empty so delete it
-            self.checkpointed_txs.remove(&update.new_tip.height);
-        }
+    /// Iterates over the unspent txouts that a tx confirmed at `height` could spend i.e. without
+    /// the coinbase outputs that haven't matured by then.
+    ///
+    /// To choose coins for a tx you're about to broadcast use the height after
+    /// [`latest_blockheight`](Self::latest_blockheight).
+    pub fn iter_spendable_unspent(&self, height: u32) -> impl Iterator<Item = LocalTxOut> + '_ {
+        self.graph
+            .iter_spendable_unspent(height)
+            .map(|(_, txout)| txout)
+    }
 
-        self.latest_blockheight = Some(update.new_tip.height);
+    pub fn iter_txout(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
+        self.graph.iter_txout().map(|(_, txout)| txout)
+    }
 
-        UpdateResult::Ok
+    pub fn get_txout(&self, txo: OutPoint) -> Option<LocalTxOut> {
+        self.graph.get_txout(txo).map(|(_, txout)| txout)
     }
 
-    pub fn clear_mempool(&mut self) {
-        let mempool = core::mem::replace(&mut self.mempool, Default::default());
-        for txid in mempool {
-            self.remove_tx(txid);
-        }
+    pub fn get_tx(&self, txid: Txid) -> Option<&AugmentedTx> {
+        self.graph.get_tx(txid)
+    }
 
-        debug_assert!(self.mempool.is_empty())
+    /// The balance of the tracker. Unconfirmed txouts count as trusted if `trust_pending` is true
+    /// (e.g. when the descriptor is only used for change).
+    pub fn balance(&self, trust_pending: bool) -> Balance {
+        self.graph.balance(|_| trust_pending)
     }
 
-    pub fn disconnect_block(&mut self, block_height: u32, block_header: BlockHash) {
-        // Can't guarantee that mempool is consistent with chain after we disconnect a block so we
-        // clear it.
-        // TODO: it would be nice if we could only delete those transactions that are
-        // inconsistent by recording the latest block they were included in.
-        self.clear_mempool();
-        if let Some((existing_block_header, _)) = self.checkpointed_txs.get(&block_height) {
-            if *existing_block_header == block_header {
-                self.invalidate_checkpoint(block_height);
-            }
-        }
+    /// The tracked txs that `txid` spends from, directly or through other tracked txs.
+    pub fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.ancestors(txid)
     }
 
-    pub fn iter_tx(&self) -> impl Iterator<Item = (Txid, &AugmentedTx)> {
-        self.txs.iter().map(|(txid, tx)| (*txid, tx))
+    /// The tracked txs that spend from `txid`, directly or through other tracked txs.
+    pub fn descendants(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.descendants(txid)
     }
 
-    pub fn iter_unspent(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
-        self.unspent
-            .iter()
-            .map(|txo| (txo, self.txouts.get(txo).expect("txout must exist")))
-            .map(|(txo, data)| self.create_txout(*txo, *data))
+    /// The unconfirmed tx `txid` together with its unconfirmed ancestors along with their total
+    /// weight and fee. This is what a CPFP tx spending from `txid` has to pay for.
+    ///
+    /// Returns `None` if `txid` isn't tracked or is confirmed.
+    pub fn unconfirmed_ancestor_package(&self, txid: Txid) -> Option<AncestorPackage> {
+        self.graph.unconfirmed_ancestor_package(txid)
     }
 
-    fn create_txout(&self, outpoint: OutPoint, data: TxOutData) -> LocalTxOut {
-        let tx = self
-            .txs
-            .get(&outpoint.txid)
-            .expect("must exist since we have the txout");
-        LocalTxOut {
-            value: data.value,
-            spent_by: data.spent_by,
-            outpoint,
-            derivation_index: data.index,
-            confirmed_at: tx.confirmation_time,
-        }
+    /// The txids of the unconfirmed txs with every tx after its parents. Txs seen first go first
+    /// where possible.
+    pub fn mempool_topological_order(&self) -> Vec<Txid> {
+        self.graph.mempool_topological_order()
     }
 
-    pub fn iter_txout(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
-        self.txouts
-            .iter()
-            .map(|(outpoint, data)| self.create_txout(*outpoint, *data))
+    /// Returns the txid of the tx that replaced `txid` if it was evicted from the tracker because
+    /// it conflicted with another tx.
+    pub fn replaced_by(&self, txid: Txid) -> Option<Txid> {
+        self.graph.replaced_by(txid)
     }
 
-    pub fn get_txout(&self, txo: OutPoint) -> Option<LocalTxOut> {
-        let data = self.txouts.get(&txo)?;
-        Some(self.create_txout(txo, *data))
+    /// Iterates over the txs that were evicted because they conflicted with another tx along with
+    /// the txid of the tx that replaced them.
+    pub fn iter_evicted(&self) -> impl Iterator<Item = (Txid, Txid)> + '_ {
+        self.graph.iter_evicted()
     }
 
-    pub fn get_tx(&self, txid: Txid) -> Option<&AugmentedTx> {
-        self.txs.get(&txid)
+    /// Iterates over the inputs (input index, txid) of the txs in the tracker that spend `outpoint`.
+    ///
+    /// The outpoint doesn't need to be owned by the tracker.
+    pub fn iter_spends(&self, outpoint: OutPoint) -> impl Iterator<Item = (u32, Txid)> + '_ {
+        self.graph.iter_spends(outpoint)
     }
 
     /// Iterates over all the script pubkeys of a descriptor.
     ///
     /// **WARNING**: never turn these into addresses or send coins to them.
     /// The tracker may not be able to find them.
-    /// To get a script you can use as an address use [`derive_next`].
+    /// To get a script you can use as an address use [`derive_new`].
     ///
-    /// [`derive_next`]: Self::derive_next
+    /// [`derive_new`]: Self::derive_new
     pub fn iter_scripts(&self) -> impl Iterator<Item = Script> {
-        let descriptor = self.descriptor.clone();
-        let end = if self.descriptor.is_deriveable() {
-            u32::MAX
-        } else {
-            1
-        };
-
-        let secp = self.secp.clone();
-        (0..end).map(move |i| {
-            descriptor
-                .derive(i)
-                .derived_descriptor(&secp)
-                .expect("the descritpor cannot need hardened derivation")
-                .script_pubkey()
-        })
+        self.derivation.iter_scripts(&self.secp)
     }
 
     pub fn script_at_index(&self, index: u32) -> Option<&Script> {
-        self.scripts.get(index as usize)
+        self.derivation.script_at_index(index)
     }
 
     /// Derives a new script pubkey which can be turned into an address.
     ///
     /// The tracker returns a new address for each call to this method and stores it internally so
-    /// it will be able to find transactions related to it.
-    pub fn derive_new(&mut self) -> (u32, &Script) {
-        debug_assert!(self.descriptor().is_deriveable() || self.next_derivation_index == 0);
-        self.store_scripts(self.next_derivation_index);
+    /// it will be able to find transactions related to it. Once every index has been handed out the
+    /// last one is returned again.
+    pub fn derive_new(&mut self) -> ((u32, &Script), ChangeSet) {
+        let index = self.derivation.index_to_reserve();
+        let mut changeset = self.store_scripts(index);
+        changeset.scripts.next_derivation_index = self.derivation.reserve_next_index();
+        let graph = &mut self.graph;
+        if let Some(last_stored_index) = self
+            .derivation
+            .store_lookahead(&self.secp, |index, script| graph.add_script(script, index))
+        {
+            changeset.scripts.last_stored_index = Some(last_stored_index);
+        }
         let script = self
-            .scripts
-            .get(self.next_derivation_index as usize)
+            .derivation
+            .script_at_index(index)
             .expect("we just derived to that index");
-        let answer = (self.next_derivation_index, script);
-        if self.descriptor().is_deriveable() {
-            self.next_derivation_index += 1;
-        }
-        answer
+        ((index, script), changeset)
     }
 
     /// Derives a new address only if we don't have one that hasn't been used
-    pub fn derive_next_unused(&mut self) -> (u32, &Script) {
+    pub fn derive_next_unused(&mut self) -> ((u32, &Script), ChangeSet) {
         let need_new = self.iter_unused_derived_scripts().next().is_none();
         // this rather strange branch is needed because of some lifetime issues
         if need_new {
             self.derive_new()
         } else {
-            self.iter_unused_derived_scripts().next().unwrap()
+            (
+                self.iter_unused_derived_scripts().next().unwrap(),
+                ChangeSet::default(),
+            )
         }
     }
 
     pub fn iter_derived_scripts(&self) -> impl Iterator<Item = &Script> {
-        self.scripts
-            .iter()
-            .take(self.next_derivation_index as usize)
+        self.derivation.iter_derived_scripts()
     }
 
     pub fn iter_unused_derived_scripts(&self) -> impl Iterator<Item = (u32, &Script)> {
@@ -505,44 +473,248 @@ impl DescriptorTracker {
     }
 
     pub fn is_used(&self, index: u32) -> bool {
-        self.script_txouts
-            .get(&index)
-            .map(|txos| !txos.is_empty())
-            .unwrap_or(false)
+        self.graph.is_used(&index)
+    }
+
+    /// Stores the script pubkeys up to and including the one at `end` so that the tracker will
+    /// recognise txouts that pay to them.
+    pub fn store_scripts(&mut self, end: u32) -> ChangeSet {
+        let graph = &mut self.graph;
+        let last_stored_index = self
+            .derivation
+            .store_scripts(end, &self.secp, |index, script| {
+                graph.add_script(script, index)
+            });
+
+        ChangeSet {
+            scripts: DerivationChanges {
+                last_stored_index,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Returns at what derivation index a script pubkey was derived at.
+    pub fn index_of_stored_script(&self, script: &Script) -> Option<u32> {
+        self.graph.index_of_script(script).cloned()
+    }
+
+    /// The maximum satisfaction weight of a descriptor
+    pub fn max_satisfaction_weight(&self) -> u32 {
+        self.derivation.max_satisfaction_weight()
+    }
+
+    pub fn dust_value(&self) -> u64 {
+        self.derivation.dust_value()
+    }
+
+    /// Prepare an input for insertion into a PSBT
+    pub fn prime_input(&self, op: OutPoint) -> Option<PrimedInput> {
+        let (index, _) = self.graph.get_txout(op)?;
+        let prev_tx = self
+            .graph
+            .get_tx(op.txid)
+            .expect("since the txout exists so mus the transaction");
+        Some(self.derivation.prime_input(&prev_tx.tx, op.vout, *index))
+    }
+}
+
+/// The script pubkeys derived from a descriptor.
+///
+/// This is the part of the trackers that deals with derivation. The trackers are responsible for
+/// telling their [`SpkTracker`] about the scripts that get stored.
+#[derive(Clone, Debug)]
+pub(crate) struct DerivedScripts {
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// The ordered script pubkeys that have been derived from the descriptor.
+    scripts: Vec<Script>,
+    /// The next derivation index the tracker should used if asked for a "new" script pubkey.
+    next_derivation_index: u32,
+    /// How many script pubkeys past the next derivation index are kept stored
+    lookahead: u32,
+}
+
+impl DerivedScripts {
+    /// Checks that the script pubkeys of `descriptor` can be derived and spent from.
+    pub fn new(
+        descriptor: Descriptor<DescriptorPublicKey>,
+        secp: &Secp256k1<VerifyOnly>,
+    ) -> Result<Self, DescriptorError> {
+        // Every index is derived along the same path so if the first one can be derived they all
+        // can (up to MAX_DERIVATION_INDEX).
+        let derived = descriptor.derive(0);
+        derived.derived_descriptor(secp).map_err(|e| match e {
+            ConversionError::HardenedChild => DescriptorError::HardenedDerivation,
+            ConversionError::HardenedWildcard => DescriptorError::HardenedWildcard,
+        })?;
+        derived
+            .max_satisfaction_weight()
+            .map_err(|_| DescriptorError::Unsatisfiable)?;
+
+        Ok(Self {
+            descriptor,
+            scripts: Default::default(),
+            next_derivation_index: 0,
+            lookahead: 0,
+        })
+    }
+
+    pub fn lookahead(&self) -> u32 {
+        self.lookahead
+    }
+
+    pub fn set_lookahead(&mut self, lookahead: u32) {
+        self.lookahead = lookahead;
+    }
+
+    pub fn descriptor(&self) -> &Descriptor<DescriptorPublicKey> {
+        &self.descriptor
+    }
+
+    pub fn next_derivation_index(&self) -> u32 {
+        self.next_derivation_index
+    }
+
+    pub fn iter_scripts(&self, secp: &Secp256k1<VerifyOnly>) -> impl Iterator<Item = Script> {
+        let descriptor = self.descriptor.clone();
+        let secp = secp.clone();
+        (0..self.index_limit()).map(move |i| derive_script(&descriptor, i, &secp))
+    }
+
+    /// One past the highest index the descriptor can be derived at.
+    fn index_limit(&self) -> u32 {
+        if self.descriptor.is_deriveable() {
+            MAX_DERIVATION_INDEX + 1
+        } else {
+            1
+        }
+    }
+
+    /// The index [`reserve_next_index`](Self::reserve_next_index) hands out. Once every index has
+    /// been handed out this stays at the last one.
+    pub fn index_to_reserve(&self) -> u32 {
+        self.next_derivation_index.min(self.index_limit() - 1)
+    }
+
+    pub fn script_at_index(&self, index: u32) -> Option<&Script> {
+        self.scripts.get(index as usize)
+    }
+
+    pub fn iter_derived_scripts(&self) -> impl Iterator<Item = &Script> {
+        self.scripts
+            .iter()
+            .take(self.next_derivation_index as usize)
+    }
+
+    /// Makes sure the next derivation index is at least `next` (as far as the descriptor can be
+    /// derived). Returns the new index if it changed.
+    pub fn bump_next_derivation_index(&mut self, next: u32) -> Option<u32> {
+        let next = next.min(self.index_limit());
+        if next > self.next_derivation_index {
+            self.next_derivation_index = next;
+            return Some(next);
+        }
+        None
+    }
+
+    /// Hands out the next derivation index. Returns the new next derivation index if it changed
+    /// (it doesn't once every index has been handed out).
+    pub fn reserve_next_index(&mut self) -> Option<u32> {
+        self.bump_next_derivation_index(self.next_derivation_index.saturating_add(1))
     }
 
-    pub fn store_scripts(&mut self, end: u32) -> bool {
+    /// Derives and stores the script pubkeys up to and including `end` calling `on_stored` for each
+    /// new one. Returns the index of the last stored script if any new ones were stored.
+    pub fn store_scripts(
+        &mut self,
+        end: u32,
+        secp: &Secp256k1<VerifyOnly>,
+        mut on_stored: impl FnMut(u32, Script),
+    ) -> Option<u32> {
         let end = match self.descriptor.is_deriveable() {
             false => 0,
-            true => end,
+            true => end.min(MAX_DERIVATION_INDEX),
         };
 
         let needed = (end + 1).saturating_sub(self.scripts.len() as u32);
         for index in self.scripts.len()..self.scripts.len() + needed as usize {
-            let script = self
-                .descriptor
-                .derive(index as u32)
-                .derived_descriptor(&self.secp)
-                .expect("the descritpor cannot need hardened derivation")
-                .script_pubkey();
+            let script = derive_script(&self.descriptor, index as u32, secp);
             self.scripts.push(script.clone());
-            self.script_indexes.insert(script.clone(), index as u32);
+            on_stored(index as u32, script);
         }
 
-        needed == 0
+        if needed > 0 {
+            Some(end)
+        } else {
+            None
+        }
     }
 
-    /// Returns at what derivation index a script pubkey was derived at.
-    pub fn index_of_stored_script(&self, script: &Script) -> Option<u32> {
-        self.script_indexes.get(script).cloned()
+    /// Stores the `lookahead` script pubkeys past the next derivation index. Returns the index of
+    /// the last stored script if any new ones were stored.
+    pub fn store_lookahead(
+        &mut self,
+        secp: &Secp256k1<VerifyOnly>,
+        on_stored: impl FnMut(u32, Script),
+    ) -> Option<u32> {
+        let end = self
+            .next_derivation_index
+            .saturating_add(self.lookahead)
+            .checked_sub(1)?;
+        self.store_scripts(end, secp, on_stored)
+    }
+
+    /// Moves the next derivation index past `index` (if it isn't already) and stores the script
+    /// pubkeys up to it along with the lookahead.
+    pub fn reveal_to(
+        &mut self,
+        index: u32,
+        secp: &Secp256k1<VerifyOnly>,
+        mut on_stored: impl FnMut(u32, Script),
+    ) -> DerivationChanges {
+        let next_derivation_index = self.bump_next_derivation_index(index.saturating_add(1));
+        let last_stored_index = self.store_scripts(index, secp, &mut on_stored);
+        DerivationChanges {
+            next_derivation_index,
+            last_stored_index: self.store_lookahead(secp, on_stored).or(last_stored_index),
+            ..Default::default()
+        }
+    }
+
+    pub fn apply_changes(
+        &mut self,
+        changes: DerivationChanges,
+        secp: &Secp256k1<VerifyOnly>,
+        on_stored: impl FnMut(u32, Script),
+    ) {
+        if let Some(lookahead) = changes.lookahead {
+            self.lookahead = lookahead;
+        }
+
+        if let Some(last_stored_index) = changes.last_stored_index {
+            self.store_scripts(last_stored_index, secp, on_stored);
+        }
+
+        if let Some(next_derivation_index) = changes.next_derivation_index {
+            self.next_derivation_index = next_derivation_index;
+        }
+    }
+
+    /// The changes that recreate the derivation state when applied to a new [`DerivedScripts`].
+    pub fn full_changes(&self) -> DerivationChanges {
+        DerivationChanges {
+            next_derivation_index: Some(self.next_derivation_index),
+            last_stored_index: (self.scripts.len() as u32).checked_sub(1),
+            lookahead: Some(self.lookahead),
+        }
     }
 
-    /// The maximum satisfaction weight of a descriptor
     pub fn max_satisfaction_weight(&self) -> u32 {
         self.descriptor
             .derive(0)
             .max_satisfaction_weight()
-            .expect("descriptor is well formed") as u32
+            .expect("checked that the descriptor is satisfiable when it was added") as u32
     }
 
     pub fn dust_value(&self) -> u64 {
@@ -553,82 +725,349 @@ impl DescriptorTracker {
             .as_sat()
     }
 
-    /// Prepare an input for insertion into a PSBT
-    pub fn prime_input(&self, op: OutPoint) -> Option<PrimedInput> {
-        let txout = self.txouts.get(&op)?;
-        let descriptor = self.descriptor().derive(txout.index);
+    /// Prepares the output `vout` of `prev_tx` paying to the script at `index` for a PSBT.
+    pub fn prime_input(&self, prev_tx: &Transaction, vout: u32, index: u32) -> PrimedInput {
+        let descriptor = self.descriptor.derive(index);
         let mut psbt_input = psbt::Input::default();
 
-        let prev_tx = self
-            .txs
-            .get(&op.txid)
-            .expect("since the txout exists so mus the transaction");
-
-        match self.descriptor().desc_type().segwit_version() {
+        match self.descriptor.desc_type().segwit_version() {
             Some(version) => {
                 if version < WitnessVersion::V1 {
-                    psbt_input.non_witness_utxo = Some(prev_tx.tx.clone());
+                    psbt_input.non_witness_utxo = Some(prev_tx.clone());
                 }
-                psbt_input.witness_utxo = Some(prev_tx.tx.output[op.vout as usize].clone());
+                psbt_input.witness_utxo = Some(prev_tx.output[vout as usize].clone());
             }
-            None => psbt_input.non_witness_utxo = Some(prev_tx.tx.clone()),
+            None => psbt_input.non_witness_utxo = Some(prev_tx.clone()),
         }
 
         psbt_input
-            .update_with_descriptor_unchecked(&self.descriptor().derive(txout.index).into())
-            .expect("conversion error cannot happen if descriptor is well formed");
+            .update_with_descriptor_unchecked(&descriptor)
+            .expect("checked that the descriptor can be derived when it was added");
 
-        let primed_input = PrimedInput {
+        PrimedInput {
             descriptor,
             psbt_input,
-        };
-
-        Some(primed_input)
+        }
     }
 }
 
+/// The highest index that can be derived without hardened derivation
+const MAX_DERIVATION_INDEX: u32 = (1 << 31) - 1;
+
+fn derive_script(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    index: u32,
+    secp: &Secp256k1<VerifyOnly>,
+) -> Script {
+    descriptor
+        .derive(index)
+        .derived_descriptor(secp)
+        .expect("checked that the descriptor can be derived when it was added")
+        .script_pubkey()
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct Update {
-    pub transactions: Vec<(PrevOuts, Transaction, Option<BlockTime>)>,
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Update<A = Option<u32>> {
+    /// The txs related to the tracker along with their previous outputs, the block they were
+    /// confirmed in and (optionally) the unix timestamp at which they were seen unconfirmed.
+    pub transactions: Vec<(PrevOuts, Transaction, Option<BlockTime>, Option<u64>)>,
     pub mempool_is_total_set: bool,
-    pub last_active_index: Option<u32>,
+    /// The highest derivation index that has a tx related to it (for each keychain in the case of a
+    /// [`KeychainUpdate`]).
+    ///
+    /// [`KeychainUpdate`]: crate::KeychainUpdate
+    pub last_active_index: A,
     /// The data in the update can be applied upon this checkpoint. If None then it is not
     /// consistent with any particular tip (apart from new tip) and so should form the base
     pub base_tip: Option<CheckPoint>,
-    /// Invalidates a particular checkpoint
-    pub invalidate: Option<CheckPoint>,
+    /// Checkpoints of the tracker (e.g. from its [`block_locator`]) that are no longer in the chain.
+    /// If there are any, every checkpoint above `base_tip` is invalidated.
+    ///
+    /// [`block_locator`]: crate::DescriptorTracker::block_locator
+    pub invalidate: Vec<CheckPoint>,
     /// The data is valid with respect to new_tip
     pub new_tip: CheckPoint,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct AugmentedTx {
-    pub tx: Transaction,
-    pub fee: u64,
-    pub feerate: f32,
+/// The serialized form of a [`DescriptorTracker`].
+///
+/// Only the descriptor and the state needed to recreate the tracker is serialized. The derived
+/// scripts, the indexes and `secp` are rebuilt when deserializing.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(crate = "serde_crate")]
+struct SerdeDescriptorTracker {
+    descriptor: Descriptor<DescriptorPublicKey>,
+    state: ChangeSet,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for DescriptorTracker {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerdeDescriptorTracker {
+            descriptor: self.descriptor().clone(),
+            state: self.full_changeset(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DescriptorTracker {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SerdeDescriptorTracker { descriptor, state } =
+            SerdeDescriptorTracker::deserialize(deserializer)?;
+        let mut tracker = DescriptorTracker::new(descriptor).map_err(serde::de::Error::custom)?;
+        tracker.apply_changeset(state);
+        Ok(tracker)
+    }
+}
+
+/// The changes made to a [`DescriptorTracker`] by an operation on it.
+///
+/// Persist these and replay them with [`DescriptorTracker::apply_changeset`] to restore the
+/// tracker's state without having to sync again.
+///
+/// `S` holds the changes to the script pubkeys the tracker knows about. For a [`DescriptorTracker`]
+/// this is a [`DerivationChanges`].
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ChangeSet<S = DerivationChanges> {
+    /// Checkpoints that were invalidated. They were removed along with all their txs.
+    pub invalidated_checkpoints: Vec<CheckPoint>,
+    /// Checkpoints whose txs were moved into a newer checkpoint. They were removed but their txs
+    /// weren't.
+    pub rebased_checkpoints: BTreeSet<u32>,
+    /// Checkpoints that were added or had txs added to them along with all of their txids.
+    pub new_checkpoints: BTreeMap<u32, (BlockHash, BTreeSet<Txid>)>,
+    /// Txs that were removed e.g. because they left the mempool or were evicted
+    pub removed_txs: BTreeSet<Txid>,
+    /// Txs that were added or whose first or last seen time changed
+    pub added_txs: BTreeMap<Txid, AugmentedTx>,
+    /// Txs that were evicted because they conflicted with another tx mapped to the tx that
+    /// replaced them.
+    pub evicted_txs: BTreeMap<Txid, Txid>,
+    /// Evicted txs that were forgotten because the tx that replaced them is deeply confirmed
+    pub pruned_evicted_txs: BTreeSet<Txid>,
+    pub latest_blockheight: Option<u32>,
+    /// Changes to the script pubkeys the tracker knows about
+    pub scripts: S,
+}
+
+/// The changes to the script pubkeys derived from a descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct DerivationChanges {
+    /// The new next derivation index if it was bumped
+    pub next_derivation_index: Option<u32>,
+    /// The index of the last stored script if more scripts were stored
+    pub last_stored_index: Option<u32>,
+    /// The new lookahead if it was set
+    pub lookahead: Option<u32>,
+}
+
+impl DerivationChanges {
+    /// Adds the changes in `other` which were made after these ones.
+    pub(crate) fn append(&mut self, other: DerivationChanges) {
+        self.next_derivation_index = other.next_derivation_index.or(self.next_derivation_index);
+        self.last_stored_index = other.last_stored_index.or(self.last_stored_index);
+        self.lookahead = other.lookahead.or(self.lookahead);
+    }
+}
+
+impl<S: Default + PartialEq> ChangeSet<S> {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl<S> ChangeSet<S> {
+    pub(crate) fn record_added(&mut self, aug_tx: AugmentedTx) {
+        let txid = aug_tx.tx.txid();
+        self.evicted_txs.remove(&txid);
+        self.added_txs.insert(txid, aug_tx);
+    }
+
+    pub(crate) fn record_removed(&mut self, txid: Txid) {
+        // Removals are applied before additions so if it was added as part of this changeset we
+        // just forget about it.
+        self.added_txs.remove(&txid);
+        self.removed_txs.insert(txid);
+    }
+
+    pub(crate) fn record_rebased(&mut self, height: u32) {
+        self.new_checkpoints.remove(&height);
+        self.rebased_checkpoints.insert(height);
+    }
+
+    pub(crate) fn record_invalidated(&mut self, checkpoints: impl IntoIterator<Item = CheckPoint>) {
+        for checkpoint in checkpoints {
+            self.new_checkpoints.remove(&checkpoint.height);
+            self.rebased_checkpoints.remove(&checkpoint.height);
+            self.invalidated_checkpoints.push(checkpoint);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct AugmentedTx {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub feerate: FeeRate,
     pub confirmation_time: Option<BlockTime>,
+    /// Whether the tx is a coinbase tx
+    pub is_coinbase: bool,
+    /// The earliest unix timestamp an update saw the tx unconfirmed at
+    pub first_seen: Option<u64>,
+    /// The latest unix timestamp an update saw the tx unconfirmed at
+    pub last_seen: Option<u64>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct LocalTxOut {
     pub value: u64,
     pub spent_by: Option<(u32, Txid)>,
     pub outpoint: OutPoint,
     pub derivation_index: u32,
     pub confirmed_at: Option<BlockTime>,
+    /// Whether the txout was created by a coinbase tx
+    pub is_coinbase: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
-struct TxOutData {
-    value: u64,
-    index: u32,
-    spent_by: Option<(u32, Txid)>,
+impl LocalTxOut {
+    /// The height of the first block that can include a tx spending this txout.
+    ///
+    /// Coinbase outputs can only be spent once they have [`COINBASE_MATURITY`] confirmations so
+    /// this is `None` for an unconfirmed one. Any other txout can be spent straight away.
+    pub fn spendable_at_height(&self) -> Option<u32> {
+        match (self.is_coinbase, self.confirmed_at) {
+            (false, _) => Some(0),
+            (true, Some(confirmed_at)) => Some(confirmed_at.height + COINBASE_MATURITY),
+            (true, None) => None,
+        }
+    }
+
+    /// Whether a tx confirmed at `height` could spend this txout.
+    pub fn is_spendable_at(&self, height: u32) -> bool {
+        self.spendable_at_height()
+            .map(|spendable_at| spendable_at <= height)
+            .unwrap_or(false)
+    }
+}
+
+/// How a tx affected the wallet. See [`HistoryEntry::direction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Direction {
+    /// The tx doesn't spend any of our txouts
+    Incoming,
+    /// The tx spends at least one of our txouts
+    Outgoing,
+}
+
+/// A tx along with how it changed the value held by our txouts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct HistoryEntry {
+    pub txid: Txid,
+    /// The total value of our txouts spent by the tx
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub sent: Amount,
+    /// The total value of our txouts created by the tx
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub received: Amount,
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub fee: Amount,
+    pub feerate: FeeRate,
+    pub confirmation_time: Option<BlockTime>,
+}
+
+impl HistoryEntry {
+    /// The change in our value caused by the tx. This is negative for a tx that pays out of the
+    /// wallet (including one that only pays us back since it still costs us the fee).
+    pub fn net_value(&self) -> SignedAmount {
+        SignedAmount::from_sat(self.received.as_sat() as i64 - self.sent.as_sat() as i64)
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.sent > Amount::ZERO {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+}
+
+/// An unconfirmed tx and its unconfirmed ancestors. See
+/// [`DescriptorTracker::unconfirmed_ancestor_package`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct AncestorPackage {
+    pub txids: HashSet<Txid>,
+    /// The total weight of the txs in the package
+    pub weight: u32,
+    /// The total fee of the txs in the package
+    pub fee: u64,
+}
+
+impl AncestorPackage {
+    /// The feerate of the package as a whole.
+    pub fn feerate(&self) -> FeeRate {
+        FeeRate::from_fee_and_weight(self.fee, self.weight)
+    }
 }
 
 pub trait MultiTracker {
     fn iter_unspent(&self) -> Box<dyn Iterator<Item = (usize, LocalTxOut)> + '_>;
     fn iter_txout(&self) -> Box<dyn Iterator<Item = (usize, LocalTxOut)> + '_>;
     fn latest_blockheight(&self) -> Option<u32>;
+    /// The combined balance of the trackers. `should_trust` is called with the index of a tracker
+    /// to decide whether its unconfirmed txouts are trusted.
+    fn balance(&self, should_trust: impl Fn(usize) -> bool) -> Balance;
     fn create_psbt<I, O>(
         &self,
         inputs: I,
@@ -662,6 +1101,13 @@ impl<'a> MultiTracker for [DescriptorTracker] {
             .max()
     }
 
+    fn balance(&self, should_trust: impl Fn(usize) -> bool) -> Balance {
+        self.iter()
+            .enumerate()
+            .map(|(i, tracker)| tracker.balance(should_trust(i)))
+            .fold(Balance::default(), |sum, balance| sum + balance)
+    }
+
     fn create_psbt<I, O>(
         &self,
         inputs: I,
@@ -671,40 +1117,54 @@ impl<'a> MultiTracker for [DescriptorTracker] {
         I: IntoIterator<Item = OutPoint>,
         O: IntoIterator<Item = TxOut>,
     {
-        let unsigned_tx = Transaction {
-            version: 0x01,
-            lock_time: 0x00,
-            input: inputs
-                .into_iter()
-                .map(|previous_output| TxIn {
-                    previous_output,
-                    ..Default::default()
-                })
-                .collect(),
-            output: outputs.into_iter().collect(),
-        };
+        create_psbt(inputs, outputs, |outpoint| {
+            self.iter()
+                .find_map(|tracker| tracker.prime_input(outpoint))
+        })
+    }
+}
 
-        let mut descriptors = BTreeMap::new();
+/// Creates a PSBT spending `inputs` to `outputs` filling in the inputs that `prime_input` knows
+/// about. Returns the descriptors of the primed inputs by input index.
+pub(crate) fn create_psbt<I, O>(
+    inputs: I,
+    outputs: O,
+    prime_input: impl Fn(OutPoint) -> Option<PrimedInput>,
+) -> (Psbt, BTreeMap<usize, Descriptor<DerivedDescriptorKey>>)
+where
+    I: IntoIterator<Item = OutPoint>,
+    O: IntoIterator<Item = TxOut>,
+{
+    let unsigned_tx = Transaction {
+        version: 0x01,
+        lock_time: 0x00,
+        input: inputs
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                ..Default::default()
+            })
+            .collect(),
+        output: outputs.into_iter().collect(),
+    };
 
-        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+    let mut descriptors = BTreeMap::new();
 
-        for ((input_index, psbt_input), txin) in psbt
-            .inputs
-            .iter_mut()
-            .enumerate()
-            .zip(&psbt.unsigned_tx.input)
-        {
-            if let Some(primed_input) = self
-                .iter()
-                .find_map(|tracker| tracker.prime_input(txin.previous_output))
-            {
-                *psbt_input = primed_input.psbt_input;
-                descriptors.insert(input_index, primed_input.descriptor);
-            }
-        }
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
 
-        (psbt, descriptors)
+    for ((input_index, psbt_input), txin) in psbt
+        .inputs
+        .iter_mut()
+        .enumerate()
+        .zip(&psbt.unsigned_tx.input)
+    {
+        if let Some(primed_input) = prime_input(txin.previous_output) {
+            *psbt_input = primed_input.psbt_input;
+            descriptors.insert(input_index, primed_input.descriptor);
+        }
     }
+
+    (psbt, descriptors)
 }
 
 #[derive(Debug, Clone)]
@@ -715,109 +1175,1597 @@ pub struct PrimedInput {
 
 #[cfg(test)]
 mod test {
-    use bitcoin::{BlockHash, Transaction, TxIn, TxOut};
-    use core::cmp::max;
+    use bitcoin::{BlockHash, Transaction, TxOut};
 
     use super::*;
+    use crate::testutils::*;
 
-    const DESCRIPTOR: &'static str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL)";
+    #[test]
+    fn spend_found_regardless_of_order() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let parent = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey: script,
+            }],
+        );
+        let parent_txo = OutPoint {
+            txid: parent.txid(),
+            vout: 0,
+        };
+        let child = spending_tx(&[parent_txo], vec![TxOut::default()]);
+
+        // the child arrives before the parent
+        let update = update_from_txs(vec![(child.clone(), None), (parent.clone(), None)], 0);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(
+            tracker.get_txout(parent_txo).unwrap().spent_by,
+            Some((0, child.txid()))
+        );
+        assert_eq!(tracker.iter_unspent().count(), 0);
+        assert_eq!(
+            tracker.iter_spends(parent_txo).collect::<Vec<_>>(),
+            vec![(0, child.txid())]
+        );
 
-    pub enum IOSpec {
-        Mine(u64, usize),
-        Other(u64),
+        // the child drops out of the mempool
+        let update = update_from_txs(vec![(parent, None)], 0);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(tracker.get_txout(parent_txo).unwrap().spent_by, None);
+        assert_eq!(tracker.iter_unspent().count(), 1);
+        assert_eq!(tracker.iter_spends(parent_txo).count(), 0);
     }
 
-    pub struct TxSpec {
-        inputs: Vec<IOSpec>,
-        outputs: Vec<IOSpec>,
-        confirmed_at: Option<u32>,
-        is_coinbase: bool,
+    #[test]
+    fn conflicts_are_evicted_with_descendants() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let parent = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey: script,
+            }],
+        );
+        let parent_txo = OutPoint {
+            txid: parent.txid(),
+            vout: 0,
+        };
+        let tx_a = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        let child_a = spending_tx(
+            &[OutPoint {
+                txid: tx_a.txid(),
+                vout: 0,
+            }],
+            vec![],
+        );
+        let mut tx_b = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        tx_b.lock_time = 1;
+
+        let mut update = update_from_txs(
+            vec![
+                (parent.clone(), Some(1)),
+                (tx_a.clone(), None),
+                (child_a.clone(), None),
+            ],
+            1,
+        );
+        update.transactions[1].3 = Some(100);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        // tx_b was seen after tx_a so it replaces it and its child
+        let mut update = update_from_txs(vec![(tx_b.clone(), None)], 1);
+        update.transactions[0].3 = Some(200);
+        update.mempool_is_total_set = false;
+        update.base_tip = tracker.latest_checkpoint();
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert!(tracker.get_tx(tx_a.txid()).is_none());
+        assert!(tracker.get_tx(child_a.txid()).is_none());
+        assert_eq!(tracker.replaced_by(tx_a.txid()), Some(tx_b.txid()));
+        assert_eq!(tracker.replaced_by(child_a.txid()), Some(tx_b.txid()));
+        assert_eq!(
+            tracker.get_txout(parent_txo).unwrap().spent_by,
+            Some((0, tx_b.txid()))
+        );
+
+        // but tx_a being confirmed beats tx_b
+        let mut update = update_from_txs(vec![(tx_a.clone(), Some(2))], 2);
+        update.base_tip = tracker.latest_checkpoint();
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert!(tracker.get_tx(tx_b.txid()).is_none());
+        assert_eq!(tracker.replaced_by(tx_b.txid()), Some(tx_a.txid()));
+        assert_eq!(tracker.replaced_by(tx_a.txid()), None);
+        assert_eq!(
+            tracker.get_txout(parent_txo).unwrap().spent_by,
+            Some((0, tx_a.txid()))
+        );
+
+        // an unconfirmed tx can't replace a confirmed one
+        let mut update = update_from_txs(vec![(tx_b.clone(), None)], 2);
+        update.base_tip = tracker.latest_checkpoint();
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert!(tracker.get_tx(tx_b.txid()).is_none());
+        assert_eq!(tracker.replaced_by(tx_b.txid()), Some(tx_a.txid()));
     }
 
-    fn create_update(scripts: Vec<Script>, txs: Vec<TxSpec>, checkpoint_height: u32) -> Update {
-        let last_active_index = txs.iter().fold(None, |lai, tx_spec| {
-            tx_spec
-                .inputs
-                .iter()
-                .chain(tx_spec.outputs.iter())
-                .fold(lai, |lai, spec| match (lai, spec) {
-                    (Some(lai), IOSpec::Mine(_, index)) => Some(max(*index as u32, lai)),
-                    (None, IOSpec::Mine(_, index)) => Some(*index as u32),
-                    _ => lai,
+    #[test]
+    fn conflict_winner_does_not_depend_on_order() {
+        let parent_txo = OutPoint {
+            txid: Txid::default(),
+            vout: 0,
+        };
+        let tx_a = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        let mut tx_b = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        tx_b.lock_time = 1;
+        let larger_txid = tx_a.txid().max(tx_b.txid());
+
+        // (seen time of tx_a, seen time of tx_b, the expected winner)
+        let cases = [
+            (None, None, larger_txid),
+            (Some(100), Some(100), larger_txid),
+            (Some(100), None, tx_a.txid()),
+            (None, Some(100), tx_b.txid()),
+            (Some(200), Some(100), tx_a.txid()),
+        ];
+        for (seen_a, seen_b, winner) in cases {
+            for a_first in [true, false] {
+                let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+                let mut update =
+                    update_from_txs(vec![(tx_a.clone(), None), (tx_b.clone(), None)], 0);
+                update.transactions[0].3 = seen_a;
+                update.transactions[1].3 = seen_b;
+                if !a_first {
+                    update.transactions.reverse();
+                }
+                assert!(matches!(
+                    tracker.apply_update(update),
+                    Ok(UpdateResult::Ok(_))
+                ));
+                assert_eq!(
+                    tracker.iter_tx().map(|(txid, _)| txid).collect::<Vec<_>>(),
+                    vec![winner],
+                    "seen times {:?} {:?} with tx_a first: {}",
+                    seen_a,
+                    seen_b,
+                    a_first
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn evicted_txs_stay_evicted_until_seen_again() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let parent_txo = OutPoint {
+            txid: Txid::default(),
+            vout: 0,
+        };
+        let tx_a = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        let child_a = spending_tx(&[OutPoint::new(tx_a.txid(), 0)], vec![]);
+        let mut tx_b = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        tx_b.lock_time = 1;
+        let mut changesets = vec![];
+
+        // (tx, when it was seen)
+        let updates = [
+            (tx_a.clone(), 100),
+            (tx_b.clone(), 200),
+            // a child of tx_a that we only find out about once tx_a has been replaced
+            (child_a.clone(), 300),
+        ];
+        for (tx, seen_at) in updates {
+            let mut update = update_from_txs(vec![(tx, None)], 0);
+            update.transactions[0].3 = Some(seen_at);
+            update.mempool_is_total_set = false;
+            match tracker.apply_update(update) {
+                Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+                res => panic!("unexpected update result {:?}", res),
+            }
+        }
+        assert!(tracker.get_tx(child_a.txid()).is_none());
+        assert_eq!(tracker.replaced_by(child_a.txid()), Some(tx_b.txid()));
+        assert_eq!(
+            changesets.last().unwrap().evicted_txs,
+            core::iter::once((child_a.txid(), tx_b.txid())).collect()
+        );
+        assert!(changesets.last().unwrap().added_txs.is_empty());
+
+        // tx_a is broadcast again after tx_b was seen so it wins this time
+        let mut update = update_from_txs(vec![(tx_a.clone(), None)], 0);
+        update.transactions[0].3 = Some(400);
+        update.mempool_is_total_set = false;
+        match tracker.apply_update(update) {
+            Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+            res => panic!("unexpected update result {:?}", res),
+        }
+        assert!(tracker.get_tx(tx_b.txid()).is_none());
+        assert_eq!(tracker.replaced_by(tx_b.txid()), Some(tx_a.txid()));
+        assert_eq!(tracker.replaced_by(tx_a.txid()), None);
+        assert_eq!(
+            tracker.iter_tx().map(|(txid, _)| txid).collect::<Vec<_>>(),
+            vec![tx_a.txid()]
+        );
+
+        let mut replayed = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        for changeset in changesets {
+            replayed.apply_changeset(changeset);
+        }
+        assert_same_state(&tracker, &replayed);
+    }
+
+    #[test]
+    fn evicted_txs_are_pruned_once_replacement_is_deep() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let parent_txo = OutPoint {
+            txid: Txid::default(),
+            vout: 0,
+        };
+        let tx_a = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        let mut tx_b = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        tx_b.lock_time = 1;
+        let mut changesets = vec![];
+
+        let updates = vec![
+            update_from_txs(vec![(tx_a.clone(), None)], 1),
+            update_from_txs(vec![(tx_b.clone(), Some(2))], 2),
+            update_from_txs(vec![], 3),
+        ];
+        for mut update in updates {
+            update.base_tip = tracker.latest_checkpoint();
+            update.mempool_is_total_set = false;
+            match tracker.apply_update(update) {
+                Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+                res => panic!("unexpected update result {:?}", res),
+            }
+            if tracker.latest_blockheight() == Some(2) {
+                // tx_b only has one confirmation
+                assert_eq!(tracker.replaced_by(tx_a.txid()), Some(tx_b.txid()));
+                assert!(tracker.prune_evicted(2).is_empty());
+            }
+        }
+
+        let changeset = tracker.prune_evicted(2);
+        assert_eq!(
+            changeset.pruned_evicted_txs,
+            core::iter::once(tx_a.txid()).collect()
+        );
+        assert_eq!(tracker.replaced_by(tx_a.txid()), None);
+        assert!(tracker.prune_evicted(2).is_empty());
+        changesets.push(changeset);
+
+        let mut replayed = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        for changeset in changesets {
+            replayed.apply_changeset(changeset);
+        }
+        assert_same_state(&tracker, &replayed);
+    }
+
+    #[test]
+    fn inconsistent_update_is_rejected_without_changes() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let tx = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey: script,
+            }],
+        );
+        let txid = tx.txid();
+
+        let update = update_from_txs(vec![(tx.clone(), Some(3))], 2);
+        assert_eq!(
+            tracker.apply_update(update),
+            Err(UpdateError::TxConfirmedAboveTip {
+                txid,
+                confirmation_height: 3,
+                tip_height: 2
+            })
+        );
+        assert_eq!(tracker.iter_tx().count(), 0);
+        assert_eq!(tracker.next_derivation_index(), 0);
+        assert_eq!(tracker.latest_blockheight(), None);
+
+        let update = update_from_txs(vec![(tx.clone(), Some(2))], 2);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let mut update = update_from_txs(vec![(tx.clone(), None)], 3);
+        update.base_tip = tracker.latest_checkpoint();
+        assert_eq!(
+            tracker.apply_update(update),
+            Err(UpdateError::InconsistentConfirmation {
+                txid,
+                existing_height: 2,
+                update_height: None
+            })
+        );
+
+        let mut update = update_from_txs(vec![(tx, Some(3))], 3);
+        update.base_tip = tracker.latest_checkpoint();
+        assert_eq!(
+            tracker.apply_update(update),
+            Err(UpdateError::InconsistentConfirmation {
+                txid,
+                existing_height: 2,
+                update_height: Some(3)
+            })
+        );
+        assert_eq!(
+            tracker
+                .get_tx(txid)
+                .unwrap()
+                .confirmation_time
+                .unwrap()
+                .height,
+            2
+        );
+        assert_eq!(tracker.latest_blockheight(), Some(2));
+        assert_eq!(
+            tracker
+                .iter_tx()
+                .filter(|(_, tx)| tx.confirmation_time.is_none())
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn update_that_disagrees_with_itself_is_rejected() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let tx = spending_tx(&[OutPoint::new(Txid::default(), 0)], vec![]);
+        let txid = tx.txid();
+
+        // (the two confirmation heights the update gives the tx, the expected error heights)
+        let cases = [
+            ((Some(2), None), (2, None)),
+            ((None, Some(2)), (2, None)),
+            ((Some(1), Some(2)), (1, Some(2))),
+            ((Some(2), Some(1)), (2, Some(1))),
+        ];
+        for ((first, second), (existing_height, update_height)) in cases {
+            let update = update_from_txs(vec![(tx.clone(), first), (tx.clone(), second)], 2);
+            assert_eq!(
+                tracker.apply_update(update),
+                Err(UpdateError::InconsistentConfirmation {
+                    txid,
+                    existing_height,
+                    update_height
                 })
-        });
-        let transactions = txs
-            .into_iter()
-            .map(|tx_spec| {
-                (
-                    match tx_spec.is_coinbase {
-                        false => PrevOuts::Spend(
-                            tx_spec
-                                .inputs
-                                .iter()
-                                .map(|in_spec| match in_spec {
-                                    IOSpec::Mine(value, index) => TxOut {
-                                        value: *value,
-                                        script_pubkey: scripts[*index].clone(),
-                                    },
-                                    IOSpec::Other(value) => TxOut {
-                                        value: *value,
-                                        script_pubkey: Default::default(),
-                                    },
-                                })
-                                .collect(),
-                        ),
-                        true => {
-                            todo!()
-                        }
-                    },
-                    Transaction {
-                        version: 1,
-                        lock_time: 0,
-                        input: if tx_spec.is_coinbase {
-                            todo!()
-                        } else {
-                            tx_spec.inputs.iter().map(|_| TxIn::default()).collect()
-                        },
-                        output: tx_spec
-                            .outputs
-                            .into_iter()
-                            .map(|out_spec| match out_spec {
-                                IOSpec::Other(value) => TxOut {
-                                    value,
-                                    script_pubkey: Script::default(),
-                                },
-                                IOSpec::Mine(value, index) => TxOut {
-                                    value,
-                                    script_pubkey: scripts[index].clone(),
-                                },
-                            })
-                            .collect(),
-                    },
-                    tx_spec.confirmed_at.map(|confirmed_at| BlockTime {
-                        height: confirmed_at,
-                        time: confirmed_at as u64,
-                    }),
-                )
+            );
+            assert_eq!(tracker.iter_tx().count(), 0);
+            assert_eq!(tracker.latest_blockheight(), None);
+        }
+
+        // listing it twice the same way is fine
+        let update = update_from_txs(vec![(tx.clone(), Some(2)), (tx, Some(2))], 2);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(tracker.iter_tx().count(), 1);
+    }
+
+    #[test]
+    fn update_not_based_on_tip_is_stale() {
+        let (mut tracker, txs) = tracker_with_checkpoints(3);
+        let before = tracker.clone();
+        let checkpoint = |height| before.checkpoint_at(height).unwrap();
+        let new_tx = spending_tx(&[OutPoint::new(txs[2].txid(), 0)], vec![]);
+        let update_based_on = |base_tip, invalidate| {
+            let mut update = update_from_txs(vec![(new_tx.clone(), Some(4))], 4);
+            update.base_tip = base_tip;
+            update.invalidate = invalidate;
+            update
+        };
+
+        let stale_updates = vec![
+            // based on an older checkpoint without invalidating the ones above it
+            update_based_on(Some(checkpoint(2)), vec![]),
+            // based on nothing when we already have checkpoints
+            update_based_on(None, vec![]),
+            // based on a block we don't have
+            update_based_on(
+                Some(CheckPoint {
+                    height: 3,
+                    hash: block_hash(100),
+                }),
+                vec![],
+            ),
+            // invalidating a block we've already replaced
+            update_based_on(
+                Some(checkpoint(2)),
+                vec![CheckPoint {
+                    height: 3,
+                    hash: block_hash(100),
+                }],
+            ),
+            // based on the block it invalidates
+            update_based_on(Some(checkpoint(3)), vec![checkpoint(3)]),
+        ];
+        for update in stale_updates {
+            assert_eq!(
+                tracker.apply_update(update.clone()),
+                Ok(UpdateResult::Stale),
+                "{:?}",
+                update
+            );
+            assert_same_state(&tracker, &before);
+        }
+
+        let update = update_based_on(
+            Some(checkpoint(2)),
+            vec![CheckPoint {
+                height: 5,
+                hash: block_hash(5),
+            }],
+        );
+        assert_eq!(
+            tracker.apply_update(update),
+            Err(UpdateError::MissingCheckpoint { height: 5 })
+        );
+        assert_same_state(&tracker, &before);
+
+        let update = update_based_on(Some(checkpoint(3)), vec![]);
+        assert!(matches!(
+            tracker.apply_update(update.clone()),
+            Ok(UpdateResult::Ok(_))
+        ));
+        // applying the same update twice is stale the second time
+        assert_eq!(tracker.apply_update(update), Ok(UpdateResult::Stale));
+    }
+
+    #[test]
+    fn checkpoint_commitment_is_independent_of_order() {
+        let txs = (0..3u32)
+            .map(|i| {
+                let mut tx =
+                    spending_tx(&[OutPoint::new(Txid::default(), i)], vec![TxOut::default()]);
+                tx.lock_time = i;
+                tx
             })
-            .collect();
-
-        Update {
-            transactions,
-            last_active_index,
-            new_tip: CheckPoint {
-                height: checkpoint_height,
-                hash: BlockHash::default(),
+            .collect::<Vec<_>>();
+
+        let mut tracker_a = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let update = update_from_txs(vec![(txs[0].clone(), Some(1))], 1);
+        assert!(matches!(
+            tracker_a.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        let commitment_at_1 = tracker_a.checkpoint_commitment(1).unwrap();
+        let mut update = update_from_txs(
+            vec![(txs[1].clone(), Some(2)), (txs[2].clone(), Some(2))],
+            2,
+        );
+        update.base_tip = tracker_a.latest_checkpoint();
+        assert!(matches!(
+            tracker_a.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(tracker_a.checkpoint_commitment(1), Some(commitment_at_1));
+
+        let mut tracker_b = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let update = update_from_txs(
+            vec![
+                (txs[2].clone(), Some(2)),
+                (txs[0].clone(), Some(1)),
+                (txs[1].clone(), Some(2)),
+            ],
+            2,
+        );
+        assert!(matches!(
+            tracker_b.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(
+            tracker_a.checkpoint_commitment(2),
+            tracker_b.checkpoint_commitment(2)
+        );
+        assert_ne!(
+            tracker_a.checkpoint_commitment(2),
+            tracker_a.checkpoint_commitment(1)
+        );
+        assert_eq!(tracker_b.checkpoint_commitment(1), None);
+    }
+
+    #[test]
+    fn commitments_locate_first_divergent_checkpoint() {
+        let (tracker_a, txs) = tracker_with_checkpoints(5);
+        let mut tracker_b = tracker_a.clone();
+        let mut other = txs[3].clone();
+        other.lock_time = 100;
+
+        // the blocks from 3 are reorged out and the tx at 4 is replaced by another
+        for (height, tx) in [(3, &txs[2]), (4, &other), (5, &txs[4])] {
+            let mut update = update_from_txs(vec![(tx.clone(), Some(height))], height);
+            update.new_tip.hash = block_hash(100 + height);
+            if height == 3 {
+                update.base_tip = tracker_b.checkpoint_at(2);
+                update.invalidate = vec![tracker_b.checkpoint_at(3).unwrap()];
+            } else {
+                update.base_tip = tracker_b.latest_checkpoint();
+            }
+            assert!(matches!(
+                tracker_b.apply_update(update),
+                Ok(UpdateResult::Ok(_))
+            ));
+        }
+
+        // the commitment only covers txids so the new block at 3 doesn't change it
+        let heights = (1..=5).collect::<Vec<u32>>();
+        let first_divergent = heights.partition_point(|height| {
+            tracker_a.checkpoint_commitment(*height) == tracker_b.checkpoint_commitment(*height)
+        });
+        assert_eq!(heights[first_divergent], 4);
+        assert_ne!(tracker_a.checkpoint_at(3), tracker_b.checkpoint_at(3));
+        assert_ne!(
+            tracker_a.checkpoint_commitment(5),
+            tracker_b.checkpoint_commitment(5)
+        );
+        assert_eq!(tracker_a.checkpoint_commitment(6), None);
+
+        // disconnecting the tip and confirming the same tx again restores the commitment
+        let mut tracker_c = tracker_a.clone();
+        tracker_c.disconnect_block(5, block_hash(5));
+        assert_eq!(tracker_c.checkpoint_commitment(5), None);
+        assert_eq!(
+            tracker_c.checkpoint_commitment(4),
+            tracker_a.checkpoint_commitment(4)
+        );
+        let mut update = update_from_txs(vec![(txs[4].clone(), Some(5))], 5);
+        update.base_tip = tracker_c.latest_checkpoint();
+        update.new_tip.hash = block_hash(5);
+        assert!(matches!(
+            tracker_c.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_same_state(&tracker_a, &tracker_c);
+    }
+
+    #[test]
+    fn replaying_changesets_restores_state() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let mut changesets = vec![tracker.set_lookahead(3)];
+
+        let ((_, script_pubkey), changeset) = tracker.derive_new();
+        let mine = TxOut {
+            value: 10_000,
+            script_pubkey: script_pubkey.clone(),
+        };
+        changesets.push(changeset);
+
+        let parent = spending_tx(&[OutPoint::new(Txid::default(), 0)], vec![mine.clone()]);
+        let parent_outpoint = OutPoint {
+            txid: parent.txid(),
+            vout: 0,
+        };
+        let child = spending_tx(&[parent_outpoint], vec![mine.clone()]);
+        let mut replacement = child.clone();
+        replacement.lock_time = 1;
+        let mut late = spending_tx(&[OutPoint::new(Txid::default(), 1)], vec![mine]);
+        late.lock_time = 2;
+
+        let updates = vec![
+            update_from_txs(vec![(parent, Some(1)), (child.clone(), None)], 1),
+            // replaces the child in a new checkpoint
+            update_from_txs(vec![(replacement.clone(), Some(3))], 3),
+            // rebases checkpoint 3 into 4
+            update_from_txs(vec![(late, Some(2))], 4),
+        ];
+
+        for mut update in updates {
+            update.base_tip = tracker.latest_checkpoint();
+            match tracker.apply_update(update) {
+                Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+                res => panic!("unexpected update result {:?}", res),
+            }
+        }
+        assert_eq!(tracker.replaced_by(child.txid()), Some(replacement.txid()));
+
+        let mut replayed = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        for changeset in changesets.iter().cloned() {
+            replayed.apply_changeset(changeset);
+        }
+        assert_same_state(&tracker, &replayed);
+
+        changesets.push(tracker.disconnect_block(4, BlockHash::default()));
+        assert_eq!(
+            changesets.last().unwrap().invalidated_checkpoints,
+            vec![CheckPoint {
+                height: 4,
+                hash: BlockHash::default()
+            }]
+        );
+        assert_eq!(tracker.latest_checkpoint().map(|cp| cp.height), Some(1));
+        replayed.apply_changeset(changesets.last().unwrap().clone());
+        assert_same_state(&tracker, &replayed);
+
+        let mut snapshot = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        snapshot.apply_changeset(tracker.full_changeset());
+        assert_same_state(&tracker, &snapshot);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&tracker).unwrap();
+            let deserialized: DescriptorTracker = serde_json::from_str(&json).unwrap();
+            assert_same_state(&tracker, &deserialized);
+            assert_eq!(
+                deserialized.iter_scripts().next(),
+                tracker.iter_scripts().next()
+            );
+        }
+    }
+
+    #[test]
+    fn changesets_only_record_what_changed() {
+        let mut tracker = DescriptorTracker::new(DERIVABLE_DESCRIPTOR.parse().unwrap()).unwrap();
+        let changeset = tracker.store_scripts(3);
+        assert_eq!(changeset.scripts.last_stored_index, Some(3));
+        assert!(tracker.store_scripts(3).is_empty());
+        assert!(tracker.store_scripts(1).is_empty());
+
+        let before = tracker.clone();
+        tracker.apply_changeset(ChangeSet::default());
+        assert_same_state(&tracker, &before);
+
+        // a tx that is added and evicted by the same update only shows up as evicted
+        let parent_txo = OutPoint::new(Txid::default(), 0);
+        let tx_a = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        let mut tx_b = spending_tx(&[parent_txo], vec![TxOut::default()]);
+        tx_b.lock_time = 1;
+        let mut update = update_from_txs(vec![(tx_a.clone(), None), (tx_b.clone(), None)], 0);
+        update.transactions[0].3 = Some(100);
+        update.transactions[1].3 = Some(200);
+        let changeset = match tracker.apply_update(update) {
+            Ok(UpdateResult::Ok(changeset)) => changeset,
+            res => panic!("unexpected update result {:?}", res),
+        };
+        assert_eq!(
+            changeset.added_txs.keys().collect::<Vec<_>>(),
+            vec![&tx_b.txid()]
+        );
+        assert_eq!(
+            changeset.evicted_txs,
+            core::iter::once((tx_a.txid(), tx_b.txid())).collect()
+        );
+
+        let mut replayed = before;
+        replayed.apply_changeset(changeset);
+        assert_same_state(&tracker, &replayed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips() {
+        fn round_trip<T>(value: &T)
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + core::fmt::Debug,
+        {
+            let json = serde_json::to_string(value).unwrap();
+            assert_eq!(&serde_json::from_str::<T>(&json).unwrap(), value);
+        }
+
+        let (tracker, txs) = tracker_with_checkpoints(3);
+        let aug_tx = AugmentedTx {
+            tx: txs[0].clone(),
+            fee: 1_000,
+            feerate: FeeRate::from_sat_per_kvb(2_500),
+            confirmation_time: Some(BlockTime {
+                height: 1,
+                time: 100,
+            }),
+            is_coinbase: true,
+            first_seen: Some(50),
+            last_seen: Some(60),
+        };
+        round_trip(&aug_tx);
+
+        let txout = tracker.iter_txout().next().unwrap();
+        round_trip(&txout);
+        round_trip(&LocalTxOut {
+            spent_by: Some((1, txs[1].txid())),
+            confirmed_at: None,
+            is_coinbase: true,
+            ..txout
+        });
+
+        round_trip(&ChangeSet {
+            invalidated_checkpoints: vec![CheckPoint {
+                height: 4,
+                hash: block_hash(4),
+            }],
+            rebased_checkpoints: core::iter::once(2).collect(),
+            new_checkpoints: core::iter::once((
+                3,
+                (block_hash(3), core::iter::once(txs[2].txid()).collect()),
+            ))
+            .collect(),
+            removed_txs: core::iter::once(txs[1].txid()).collect(),
+            added_txs: core::iter::once((txs[0].txid(), aug_tx)).collect(),
+            evicted_txs: core::iter::once((txs[1].txid(), txs[2].txid())).collect(),
+            pruned_evicted_txs: core::iter::once(txs[0].txid()).collect(),
+            latest_blockheight: Some(3),
+            scripts: DerivationChanges {
+                next_derivation_index: Some(1),
+                last_stored_index: Some(5),
+                lookahead: Some(5),
             },
-            invalidate: None,
-            mempool_is_total_set: true,
-            base_tip: None,
+        });
+        round_trip(&tracker.full_changeset());
+    }
+
+    /// A tracker with a checkpoint at each height from 1 to `tip` with a tx confirmed in each.
+    fn tracker_with_checkpoints(tip: u32) -> (DescriptorTracker, Vec<Transaction>) {
+        tracker_with_checkpoints_at(1..=tip)
+    }
+
+    /// A tracker with a checkpoint at each of `heights` (in ascending order) with a tx confirmed
+    /// in each.
+    fn tracker_with_checkpoints_at(
+        heights: impl IntoIterator<Item = u32>,
+    ) -> (DescriptorTracker, Vec<Transaction>) {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let mine = TxOut {
+            value: 10_000,
+            script_pubkey: tracker.iter_scripts().next().unwrap(),
+        };
+        let mut txs = vec![];
+        for height in heights {
+            let mut tx = spending_tx(
+                &[OutPoint::new(Txid::default(), height)],
+                vec![mine.clone()],
+            );
+            tx.lock_time = height;
+            let mut update = update_from_txs(vec![(tx.clone(), Some(height))], height);
+            update.base_tip = tracker.latest_checkpoint();
+            update.new_tip.hash = block_hash(height);
+            assert!(matches!(
+                tracker.apply_update(update),
+                Ok(UpdateResult::Ok(_))
+            ));
+            txs.push(tx);
+        }
+        (tracker, txs)
+    }
+
+    #[test]
+    fn reorg_invalidates_checkpoints_above_base_tip() {
+        let (mut tracker, txs) = tracker_with_checkpoints(30);
+        let locator = tracker.block_locator();
+        assert_eq!(
+            locator.iter().map(|cp| cp.height).collect::<Vec<_>>(),
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 1]
+        );
+
+        // the heights are spaced out even where checkpoints are missing
+        let (sparse, _) = tracker_with_checkpoints_at((1..=50).chain((60..=150).step_by(10)));
+        assert_eq!(
+            sparse
+                .block_locator()
+                .iter()
+                .map(|cp| cp.height)
+                .collect::<Vec<_>>(),
+            vec![150, 140, 130, 120, 110, 100, 90, 80, 70, 60, 50, 46, 38, 22, 1]
+        );
+        assert!(DescriptorTracker::new(DESCRIPTOR.parse().unwrap())
+            .unwrap()
+            .block_locator()
+            .is_empty());
+
+        // the blocks from 12 were reorged out so only the checkpoint at 7 is still in the chain
+        let mut update = update_from_txs(vec![(txs[11].clone(), Some(13))], 31);
+        update.base_tip = Some(locator[12]);
+        update.invalidate = locator[..12].to_vec();
+        update.new_tip.hash = block_hash(100);
+        let changeset = match tracker.apply_update(update.clone()) {
+            Ok(UpdateResult::Ok(changeset)) => changeset,
+            res => panic!("unexpected update result {:?}", res),
+        };
+        assert_eq!(changeset.invalidated_checkpoints.len(), 23);
+        assert_eq!(
+            tracker
+                .iter_checkpoints()
+                .map(|(cp, _)| cp.height)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5, 6, 7, 31]
+        );
+        assert_eq!(
+            tracker
+                .get_tx(txs[11].txid())
+                .and_then(|tx| tx.confirmation_time)
+                .map(|time| time.height),
+            Some(13)
+        );
+        assert!(tracker.get_tx(txs[20].txid()).is_none());
+
+        assert_eq!(
+            tracker.apply_update(update),
+            Err(UpdateError::MissingCheckpoint { height: 30 })
+        );
+    }
+
+    #[test]
+    fn pruned_checkpoints_move_txs_to_newer_checkpoints() {
+        let (mut tracker, txs) = tracker_with_checkpoints(30);
+        let before = tracker.clone();
+        let checkpoint_of = |tracker: &DescriptorTracker, txid: Txid| {
+            tracker
+                .iter_checkpoints()
+                .find(|(_, txids)| txids.contains(&txid))
+                .map(|(cp, _)| cp.height)
+        };
+
+        let changeset = tracker.prune_checkpoints(5);
+        assert_eq!(
+            tracker
+                .iter_checkpoints()
+                .map(|(cp, _)| cp.height)
+                .collect::<Vec<_>>(),
+            vec![10, 18, 22, 24, 25, 26, 27, 28, 29, 30]
+        );
+        assert_eq!(checkpoint_of(&tracker, txs[4].txid()), Some(10));
+        assert_eq!(checkpoint_of(&tracker, txs[22].txid()), Some(24));
+        for (checkpoint, _) in tracker.iter_checkpoints() {
+            assert_eq!(
+                tracker.checkpoint_commitment(checkpoint.height),
+                before.checkpoint_commitment(checkpoint.height)
+            );
+        }
+        assert_eq!(tracker.iter_tx().count(), 30);
+
+        let mut replayed = before;
+        replayed.apply_changeset(changeset);
+        assert_same_state(&tracker, &replayed);
+        assert!(tracker.prune_checkpoints(5).is_empty());
+    }
+
+    #[test]
+    fn pruning_checkpoints_edge_cases() {
+        let mut empty = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        assert!(empty.prune_checkpoints(0).is_empty());
+
+        let (mut tracker, _) = tracker_with_checkpoints(5);
+        let before = tracker.clone();
+        assert!(tracker.prune_checkpoints(5).is_empty());
+        assert!(tracker.prune_checkpoints(100).is_empty());
+        assert_same_state(&tracker, &before);
+
+        // the latest checkpoint is always kept
+        let mut keep_one = tracker.clone();
+        let changeset = tracker.prune_checkpoints(0);
+        assert_eq!(changeset, keep_one.prune_checkpoints(1));
+        assert_eq!(
+            tracker
+                .iter_checkpoints()
+                .map(|(cp, _)| cp.height)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 4, 5]
+        );
+        assert_eq!(tracker.iter_tx().count(), 5);
+    }
+
+    #[test]
+    fn coinbase_outputs_mature() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let scripts = tracker.iter_scripts().take(1).collect::<Vec<_>>();
+        let update = create_update(
+            scripts,
+            vec![TxSpec {
+                inputs: vec![],
+                outputs: vec![IOSpec::Mine(50_000, 0)],
+                confirmed_at: Some(10),
+                is_coinbase: true,
+            }],
+            10,
+        );
+        let coinbase = update.transactions[0].1.clone();
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let txout = tracker.iter_unspent().next().unwrap();
+        assert!(txout.is_coinbase);
+        assert!(tracker.get_tx(coinbase.txid()).unwrap().is_coinbase);
+        assert_eq!(txout.spendable_at_height(), Some(110));
+        assert_eq!(tracker.iter_spendable_unspent(109).count(), 0);
+        assert_eq!(tracker.iter_spendable_unspent(110).count(), 1);
+        assert_eq!(tracker.balance(false).immature, Amount::from_sat(50_000));
+        assert_eq!(tracker.iter_spends(OutPoint::null()).count(), 0);
+
+        // only the `PrevOuts` of a tx decide whether it's a coinbase
+        let lookalike = spending_tx(&[OutPoint::null()], vec![TxOut::default()]);
+        let mut update = update_from_txs(vec![(lookalike.clone(), None)], 10);
+        update.base_tip = tracker.latest_checkpoint();
+        update.mempool_is_total_set = false;
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert!(!tracker.get_tx(lookalike.txid()).unwrap().is_coinbase);
+        assert_eq!(
+            tracker.iter_spends(OutPoint::null()).collect::<Vec<_>>(),
+            vec![(0, lookalike.txid())]
+        );
+
+        let mut restored = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        restored.apply_changeset(tracker.full_changeset());
+        assert_same_state(&tracker, &restored);
+        assert_eq!(
+            restored.iter_spends(OutPoint::null()).collect::<Vec<_>>(),
+            vec![(0, lookalike.txid())]
+        );
+    }
+
+    #[test]
+    fn coinbase_maturity_boundary() {
+        let txout = LocalTxOut {
+            value: 50_000,
+            spent_by: None,
+            outpoint: OutPoint::null(),
+            derivation_index: 0,
+            confirmed_at: Some(BlockTime { height: 0, time: 0 }),
+            is_coinbase: true,
+        };
+        assert_eq!(txout.spendable_at_height(), Some(COINBASE_MATURITY));
+        assert!(!txout.is_spendable_at(COINBASE_MATURITY - 1));
+        assert!(txout.is_spendable_at(COINBASE_MATURITY));
+
+        let unconfirmed = LocalTxOut {
+            confirmed_at: None,
+            ..txout
+        };
+        assert_eq!(unconfirmed.spendable_at_height(), None);
+        assert!(!unconfirmed.is_spendable_at(u32::MAX));
+
+        let not_coinbase = LocalTxOut {
+            is_coinbase: false,
+            ..unconfirmed
+        };
+        assert_eq!(not_coinbase.spendable_at_height(), Some(0));
+        assert!(not_coinbase.is_spendable_at(0));
+
+        // a coinbase tx we somehow learn about before it's confirmed is never spendable
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let scripts = tracker.iter_scripts().take(1).collect::<Vec<_>>();
+        let update = create_update(
+            scripts,
+            vec![TxSpec {
+                inputs: vec![],
+                outputs: vec![IOSpec::Mine(50_000, 0)],
+                confirmed_at: None,
+                is_coinbase: true,
+            }],
+            0,
+        );
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(tracker.iter_unspent().count(), 1);
+        assert_eq!(tracker.iter_spendable_unspent(u32::MAX).count(), 0);
+        assert_eq!(tracker.balance(true).immature, Amount::from_sat(50_000));
+    }
+
+    #[test]
+    fn balance_only_counts_unspent_txouts() {
+        let (mut tracker, txs) = tracker_with_checkpoints(2);
+        let empty = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        assert_eq!(empty.balance(true), Balance::default());
+        assert_eq!(tracker.balance(false).confirmed, Amount::from_sat(20_000));
+
+        // spend one of the confirmed txouts back to ourselves
+        let change = spending_tx(
+            &[OutPoint::new(txs[1].txid(), 0)],
+            vec![TxOut {
+                value: 9_000,
+                script_pubkey: tracker.iter_scripts().next().unwrap(),
+            }],
+        );
+        let mut update = update_from_txs(vec![(change, None)], 2);
+        update.base_tip = tracker.latest_checkpoint();
+        update.new_tip.hash = block_hash(2);
+        update.mempool_is_total_set = false;
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(
+            tracker.balance(false),
+            Balance {
+                untrusted_pending: Amount::from_sat(9_000),
+                confirmed: Amount::from_sat(10_000),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            tracker.balance(true),
+            Balance {
+                trusted_pending: Amount::from_sat(9_000),
+                confirmed: Amount::from_sat(10_000),
+                ..Default::default()
+            }
+        );
+
+        let trackers = [tracker, empty];
+        assert_eq!(trackers[..].balance(|i| i == 0), trackers[0].balance(true));
+        assert_eq!(trackers[..].balance(|i| i == 1), trackers[0].balance(false));
+    }
+
+    #[test]
+    fn mempool_txs_are_pruned_by_last_seen() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let pay = |vout| {
+            spending_tx(
+                &[OutPoint::new(Txid::default(), vout)],
+                vec![TxOut {
+                    value: 1_000,
+                    script_pubkey: script.clone(),
+                }],
+            )
+        };
+        let (a, b) = (pay(1), pay(2));
+        let partial_update = |txs: Vec<(Transaction, Option<u32>, u64)>| {
+            let seen_at = txs
+                .iter()
+                .map(|(_, _, seen_at)| Some(*seen_at))
+                .collect::<Vec<_>>();
+            let mut update = update_from_txs(
+                txs.into_iter()
+                    .map(|(tx, height, _)| (tx, height))
+                    .collect(),
+                1,
+            );
+            for (tx, seen_at) in update.transactions.iter_mut().zip(seen_at) {
+                tx.3 = seen_at;
+            }
+            update.mempool_is_total_set = false;
+            update
+        };
+
+        let update = partial_update(vec![(a.clone(), None, 100), (b.clone(), None, 120)]);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        match tracker
+            .apply_update(partial_update(vec![(a.clone(), None, 150)]))
+            .unwrap()
+        {
+            UpdateResult::Ok(changeset) => assert!(changeset.added_txs.contains_key(&a.txid())),
+            UpdateResult::Stale => panic!("update should apply"),
+        }
+        let a_tx = tracker.get_tx(a.txid()).unwrap();
+        assert_eq!((a_tx.first_seen, a_tx.last_seen), (Some(100), Some(150)));
+
+        let changeset = tracker.prune_mempool_older_than(130);
+        assert_eq!(changeset.removed_txs, [b.txid()].into());
+        assert!(tracker.get_tx(b.txid()).is_none());
+
+        // confirming it keeps when we saw it in the mempool
+        let update = partial_update(vec![(a.clone(), Some(1), 160)]);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        let a_tx = tracker.get_tx(a.txid()).unwrap();
+        assert_eq!((a_tx.first_seen, a_tx.last_seen), (Some(100), Some(150)));
+        assert!(tracker.prune_mempool_older_than(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn mempool_pruning_cutoff_is_exclusive() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let pay = |vout| spending_tx(&[OutPoint::new(Txid::default(), vout)], vec![]);
+        let (a, never_seen) = (pay(1), pay(2));
+        let mut changesets = vec![];
+        // seen times that arrive out of order or repeat
+        for seen_at in [Some(200), Some(100), Some(150), Some(200), None] {
+            let mut update =
+                update_from_txs(vec![(a.clone(), None), (never_seen.clone(), None)], 0);
+            update.transactions[0].3 = seen_at;
+            update.mempool_is_total_set = false;
+            match tracker.apply_update(update) {
+                Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+                res => panic!("unexpected update result {:?}", res),
+            }
+        }
+        let a_tx = tracker.get_tx(a.txid()).unwrap();
+        assert_eq!((a_tx.first_seen, a_tx.last_seen), (Some(100), Some(200)));
+        // only the updates that widened the seen times record a change to the tx
+        assert_eq!(
+            changesets
+                .iter()
+                .map(|changeset| changeset.added_txs.contains_key(&a.txid()))
+                .collect::<Vec<_>>(),
+            vec![true, true, false, false, false]
+        );
+
+        assert!(tracker.prune_mempool_older_than(200).is_empty());
+        let changeset = tracker.prune_mempool_older_than(201);
+        assert_eq!(changeset.removed_txs, [a.txid()].into());
+        // we don't know how old a tx without a seen time is so it stays
+        assert!(tracker.get_tx(never_seen.txid()).is_some());
+        assert!(tracker.prune_mempool_older_than(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn hardened_descriptor_is_rejected() {
+        let xpub = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+        assert_eq!(
+            DescriptorTracker::new(alloc::format!("wpkh({}/0/*')", xpub).parse().unwrap())
+                .unwrap_err(),
+            DescriptorError::HardenedWildcard
+        );
+        assert_eq!(
+            DescriptorTracker::new(alloc::format!("wpkh({}/0'/*)", xpub).parse().unwrap())
+                .unwrap_err(),
+            DescriptorError::HardenedDerivation
+        );
+        assert!(DescriptorTracker::new(DERIVABLE_DESCRIPTOR.parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn lookahead_finds_unrevealed_scripts() {
+        let mut tracker = DescriptorTracker::new(DERIVABLE_DESCRIPTOR.parse().unwrap()).unwrap();
+        let changeset = tracker.set_lookahead(5);
+        assert_eq!(changeset.scripts.last_stored_index, Some(4));
+        assert_eq!(changeset.scripts.lookahead, Some(5));
+        assert_eq!(tracker.next_derivation_index(), 0);
+
+        let scripts = tracker.iter_scripts().take(8).collect::<Vec<_>>();
+        let pay = |vout, index: usize| {
+            spending_tx(
+                &[OutPoint::new(Txid::default(), vout)],
+                vec![TxOut {
+                    value: 1_000,
+                    script_pubkey: scripts[index].clone(),
+                }],
+            )
+        };
+        // index 7 is only stored once we find the payment to index 3
+        let mut update = update_from_txs(vec![(pay(1, 3), Some(1)), (pay(2, 7), Some(1))], 1);
+        update.last_active_index = None;
+        let changeset = match tracker.apply_update(update).unwrap() {
+            UpdateResult::Ok(changeset) => changeset,
+            UpdateResult::Stale => panic!("update should apply"),
+        };
+        assert_eq!(tracker.iter_txout().count(), 2);
+        assert_eq!(tracker.next_derivation_index(), 8);
+        assert_eq!(
+            tracker.script_at_index(12),
+            Some(&tracker.iter_scripts().nth(12).unwrap())
+        );
+        assert_eq!(
+            changeset.scripts,
+            DerivationChanges {
+                next_derivation_index: Some(8),
+                last_stored_index: Some(12),
+                lookahead: None,
+            }
+        );
+
+        let (_, changeset) = tracker.derive_new();
+        assert_eq!(changeset.scripts.last_stored_index, Some(13));
+    }
+
+    #[test]
+    fn lookahead_edge_cases() {
+        let mut tracker = DescriptorTracker::new(DERIVABLE_DESCRIPTOR.parse().unwrap()).unwrap();
+        let changeset = tracker.set_lookahead(0);
+        assert_eq!(changeset.scripts.last_stored_index, None);
+        assert_eq!(tracker.script_at_index(0), None);
+
+        assert_eq!(tracker.set_lookahead(5).scripts.last_stored_index, Some(4));
+        // lowering the lookahead keeps the scripts that are already stored
+        let changeset = tracker.set_lookahead(2);
+        assert_eq!(
+            changeset.scripts,
+            DerivationChanges {
+                lookahead: Some(2),
+                ..Default::default()
+            }
+        );
+        assert!(tracker.script_at_index(4).is_some());
+        assert_eq!(tracker.script_at_index(5), None);
+        let (_, changeset) = tracker.derive_new();
+        assert_eq!(changeset.scripts.last_stored_index, None);
+
+        // a payment past the stored scripts is missed
+        let script = tracker.iter_scripts().nth(5).unwrap();
+        let tx = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey: script,
+            }],
+        );
+        let mut update = update_from_txs(vec![(tx, None)], 0);
+        update.last_active_index = None;
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        assert_eq!(tracker.iter_txout().count(), 0);
+        assert_eq!(tracker.next_derivation_index(), 1);
+
+        // a descriptor without a wildcard only ever has the one script
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        tracker.set_lookahead(10);
+        assert!(tracker.script_at_index(0).is_some());
+        assert_eq!(tracker.script_at_index(1), None);
+        assert_eq!(tracker.lookahead(), 10);
+    }
+
+    #[test]
+    fn out_of_range_indexes_are_clamped() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        tracker.set_lookahead(u32::MAX);
+        let mut update = update_from_txs(vec![], 0);
+        update.last_active_index = Some(u32::MAX);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        // a descriptor without a wildcard only has index 0
+        assert_eq!(tracker.next_derivation_index(), 1);
+        let ((index, _), changeset) = tracker.derive_new();
+        assert_eq!(index, 0);
+        assert!(changeset.is_empty());
+
+        // a backend can't push the next index past the last one that can be derived
+        let descriptor = DERIVABLE_DESCRIPTOR.parse().unwrap();
+        let mut derivation =
+            DerivedScripts::new(descriptor, &Secp256k1::verification_only()).unwrap();
+        assert_eq!(
+            derivation.bump_next_derivation_index(u32::MAX),
+            Some(MAX_DERIVATION_INDEX + 1)
+        );
+        assert_eq!(derivation.index_to_reserve(), MAX_DERIVATION_INDEX);
+        assert_eq!(derivation.reserve_next_index(), None);
+        assert_eq!(derivation.next_derivation_index(), MAX_DERIVATION_INDEX + 1);
+    }
+
+    #[test]
+    fn ancestry_of_unconfirmed_chain() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let ours = |value| TxOut {
+            value,
+            script_pubkey: script.clone(),
+        };
+        let a = spending_tx(&[OutPoint::new(Txid::default(), 1)], vec![ours(10_000)]);
+        let b = spending_tx(&[OutPoint::new(a.txid(), 0)], vec![ours(9_000)]);
+        let c = spending_tx(&[OutPoint::new(b.txid(), 0)], vec![ours(8_500)]);
+        let d = spending_tx(&[OutPoint::new(Txid::default(), 2)], vec![ours(1_000)]);
+
+        let mut update = update_from_txs(
+            vec![
+                (a.clone(), Some(1)),
+                (b.clone(), None),
+                (c.clone(), None),
+                (d.clone(), None),
+            ],
+            1,
+        );
+        update.transactions[1].0 = PrevOuts::Spend(vec![ours(10_000)]);
+        update.transactions[2].0 = PrevOuts::Spend(vec![ours(9_000)]);
+        // the child was seen before its parent
+        for (tx, seen_at) in
+            update
+                .transactions
+                .iter_mut()
+                .zip([None, Some(200), Some(100), Some(50)])
+        {
+            tx.3 = seen_at;
+        }
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        assert_eq!(tracker.ancestors(c.txid()), [a.txid(), b.txid()].into());
+        assert_eq!(tracker.descendants(a.txid()), [b.txid(), c.txid()].into());
+        assert!(tracker.descendants(d.txid()).is_empty());
+
+        let package = tracker.unconfirmed_ancestor_package(c.txid()).unwrap();
+        assert_eq!(package.txids, [b.txid(), c.txid()].into());
+        assert_eq!(package.fee, 1_500);
+        assert_eq!(package.weight, (b.weight() + c.weight()) as u32);
+        assert!(tracker.unconfirmed_ancestor_package(a.txid()).is_none());
+
+        assert_eq!(
+            tracker.mempool_topological_order(),
+            vec![d.txid(), b.txid(), c.txid()]
+        );
+    }
+
+    #[test]
+    fn mempool_order_puts_unseen_txs_last() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let output = || TxOut {
+            value: 1_000,
+            script_pubkey: Script::default(),
+        };
+        let unseen = spending_tx(&[OutPoint::new(Txid::default(), 1)], vec![output()]);
+        let later = spending_tx(&[OutPoint::new(Txid::default(), 2)], vec![output()]);
+        let earlier = spending_tx(&[OutPoint::new(Txid::default(), 3)], vec![output()]);
+        let child = spending_tx(&[OutPoint::new(unseen.txid(), 0)], vec![output()]);
+
+        let mut update = update_from_txs(
+            [&unseen, &later, &earlier, &child]
+                .into_iter()
+                .map(|tx| (tx.clone(), None))
+                .collect(),
+            0,
+        );
+        // the child was seen first but has to wait for its unseen parent
+        for (tx, seen_at) in
+            update
+                .transactions
+                .iter_mut()
+                .zip([None, Some(200), Some(100), Some(50)])
+        {
+            tx.0 = PrevOuts::Spend(vec![output()]);
+            tx.3 = seen_at;
         }
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        assert_eq!(
+            tracker.mempool_topological_order(),
+            vec![earlier.txid(), later.txid(), unseen.txid(), child.txid()]
+        );
+    }
+
+    #[test]
+    fn ancestry_of_diamond_and_missing_parents() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let outputs = |n| {
+            vec![
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: Script::default(),
+                };
+                n
+            ]
+        };
+        let a = spending_tx(&[OutPoint::new(Txid::default(), 1)], outputs(2));
+        let b = spending_tx(&[OutPoint::new(a.txid(), 0)], outputs(1));
+        let c = spending_tx(&[OutPoint::new(a.txid(), 1)], outputs(3));
+        let d = spending_tx(
+            &[OutPoint::new(b.txid(), 0), OutPoint::new(c.txid(), 0)],
+            outputs(1),
+        );
+        // spends two outputs of the same parent
+        let e = spending_tx(
+            &[OutPoint::new(c.txid(), 1), OutPoint::new(c.txid(), 2)],
+            outputs(1),
+        );
+        let untracked = spending_tx(&[OutPoint::new(Txid::default(), 2)], outputs(1));
+        let orphan = spending_tx(&[OutPoint::new(untracked.txid(), 0)], outputs(1));
+
+        let mut update = update_from_txs(
+            [&e, &d, &c, &b, &a, &orphan]
+                .into_iter()
+                .map(|tx| (tx.clone(), None))
+                .collect(),
+            0,
+        );
+        for (prev_outs, tx, _, _) in &mut update.transactions {
+            *prev_outs = PrevOuts::Spend(outputs(tx.input.len()));
+        }
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        assert_eq!(
+            tracker.ancestors(d.txid()),
+            [a.txid(), b.txid(), c.txid()].into()
+        );
+        assert_eq!(tracker.ancestors(e.txid()), [a.txid(), c.txid()].into());
+        assert_eq!(
+            tracker.descendants(a.txid()),
+            [b.txid(), c.txid(), d.txid(), e.txid()].into()
+        );
+        assert_eq!(
+            tracker
+                .unconfirmed_ancestor_package(d.txid())
+                .unwrap()
+                .txids,
+            [a.txid(), b.txid(), c.txid(), d.txid()].into()
+        );
+
+        // the parent of the orphan isn't tracked so it has no ancestors
+        assert!(tracker.ancestors(orphan.txid()).is_empty());
+        assert_eq!(
+            tracker
+                .unconfirmed_ancestor_package(orphan.txid())
+                .unwrap()
+                .txids,
+            [orphan.txid()].into()
+        );
+        assert!(tracker.ancestors(untracked.txid()).is_empty());
+        assert!(tracker.descendants(untracked.txid()).is_empty());
+        assert!(tracker
+            .unconfirmed_ancestor_package(untracked.txid())
+            .is_none());
+
+        let order = tracker.mempool_topological_order();
+        assert_eq!(order.len(), 6);
+        let position = |txid| order.iter().position(|ordered| *ordered == txid).unwrap();
+        for (child, parent) in [(&b, &a), (&c, &a), (&d, &b), (&d, &c), (&e, &c)] {
+            assert!(position(child.txid()) > position(parent.txid()));
+        }
+    }
+
+    #[test]
+    fn history_is_most_recent_first() {
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
+        let script = tracker.iter_scripts().next().unwrap();
+        let ours = |value| TxOut {
+            value,
+            script_pubkey: script.clone(),
+        };
+        let older = spending_tx(&[OutPoint::new(Txid::default(), 1)], vec![ours(1_000)]);
+        let parent = spending_tx(&[OutPoint::new(Txid::default(), 2)], vec![ours(10_000)]);
+        let child = spending_tx(
+            &[OutPoint::new(parent.txid(), 0)],
+            vec![
+                TxOut {
+                    value: 7_000,
+                    script_pubkey: Script::default(),
+                },
+                ours(2_000),
+            ],
+        );
+        let grandchild = spending_tx(&[OutPoint::new(child.txid(), 1)], vec![ours(1_500)]);
+
+        let mut update = update_from_txs(
+            vec![
+                (grandchild.clone(), None),
+                (parent.clone(), Some(5)),
+                (older.clone(), Some(3)),
+            ],
+            5,
+        );
+        update.transactions.push((
+            PrevOuts::Spend(vec![ours(10_000)]),
+            child.clone(),
+            None,
+            None,
+        ));
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let history = tracker.history(0, usize::MAX);
+        assert_eq!(
+            history.iter().map(|entry| entry.txid).collect::<Vec<_>>(),
+            vec![grandchild.txid(), child.txid(), parent.txid(), older.txid()]
+        );
+        let child_entry = history[1];
+        assert_eq!(child_entry.sent, Amount::from_sat(10_000));
+        assert_eq!(child_entry.received, Amount::from_sat(2_000));
+        assert_eq!(child_entry.net_value(), SignedAmount::from_sat(-8_000));
+        assert_eq!(child_entry.fee, Amount::from_sat(1_000));
+        assert_eq!(child_entry.direction(), Direction::Outgoing);
+        assert_eq!(history[2].net_value(), SignedAmount::from_sat(10_000));
+        assert_eq!(history[2].direction(), Direction::Incoming);
+        assert_eq!(history[2].confirmation_time.unwrap().height, 5);
+
+        assert_eq!(tracker.history(1, 2), history[1..3].to_vec());
+        assert!(tracker.history(4, 10).is_empty());
+        assert_pages_match(&tracker, &history);
+    }
+
+    #[test]
+    fn history_pages_span_checkpoints() {
+        let (mut tracker, txs) = tracker_with_checkpoints(4);
+        let unconfirmed = spending_tx(&[OutPoint::new(txs[3].txid(), 0)], vec![]);
+        let mut update = update_from_txs(vec![(unconfirmed.clone(), None)], 4);
+        update.base_tip = tracker.latest_checkpoint();
+        update.new_tip.hash = block_hash(4);
+        update.mempool_is_total_set = false;
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let history = tracker.history(0, usize::MAX);
+        assert_eq!(
+            history.iter().map(|entry| entry.txid).collect::<Vec<_>>(),
+            core::iter::once(&unconfirmed)
+                .chain(txs.iter().rev())
+                .map(|tx| tx.txid())
+                .collect::<Vec<_>>()
+        );
+        assert_pages_match(&tracker, &history);
+
+        // merging checkpoints doesn't change the history
+        assert!(!tracker.prune_checkpoints(1).is_empty());
+        assert_eq!(tracker.history(0, usize::MAX), history);
+        assert_pages_match(&tracker, &history);
+    }
+
+    /// Checks that every page of the tracker's history is the same slice of `history`.
+    fn assert_pages_match(tracker: &DescriptorTracker, history: &[HistoryEntry]) {
+        for offset in 0..=history.len() + 1 {
+            for limit in 0..=history.len() + 1 {
+                let end = (offset + limit).min(history.len());
+                assert_eq!(
+                    tracker.history(offset, limit),
+                    history.get(offset..end).unwrap_or(&[]),
+                    "offset {} limit {}",
+                    offset,
+                    limit
+                );
+            }
+        }
+    }
+
+    fn assert_same_state(a: &DescriptorTracker, b: &DescriptorTracker) {
+        assert_eq!(
+            a.iter_tx().collect::<Vec<_>>(),
+            b.iter_tx().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            a.iter_txout().collect::<Vec<_>>(),
+            b.iter_txout().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            a.iter_unspent().collect::<Vec<_>>(),
+            b.iter_unspent().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            a.iter_checkpoints().collect::<Vec<_>>(),
+            b.iter_checkpoints().collect::<Vec<_>>()
+        );
+        for (checkpoint, _) in a.iter_checkpoints() {
+            assert_eq!(
+                a.checkpoint_commitment(checkpoint.height),
+                b.checkpoint_commitment(checkpoint.height)
+            );
+        }
+        assert_eq!(
+            a.iter_evicted().collect::<Vec<_>>(),
+            b.iter_evicted().collect::<Vec<_>>()
+        );
+        assert_eq!(a.next_derivation_index(), b.next_derivation_index());
+        assert_eq!(a.lookahead(), b.lookahead());
+        assert_eq!(a.latest_blockheight(), b.latest_blockheight());
     }
 
     #[test]
     fn apply_update_no_checkpoint() {
-        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap());
+        let mut tracker = DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap();
         let scripts = tracker.iter_scripts().take(5).collect::<Vec<_>>();
         use IOSpec::*;
 
@@ -832,7 +2780,10 @@ mod test {
             0,
         );
 
-        assert_eq!(tracker.apply_update(update.clone()), UpdateResult::Ok);
+        assert!(matches!(
+            tracker.apply_update(update.clone()),
+            Ok(UpdateResult::Ok(_))
+        ));
 
         let txouts = tracker.iter_txout().collect::<Vec<_>>();
         let txs = tracker.iter_tx().collect::<Vec<_>>();
@@ -850,7 +2801,10 @@ mod test {
             hash: update.new_tip.hash,
         };
 
-        assert_eq!(tracker.apply_update(update), UpdateResult::Ok);
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
 
         let txs = tracker.iter_tx().collect::<Vec<_>>();
         let checkpoints = tracker.iter_checkpoints().collect::<Vec<_>>();
@@ -0,0 +1,135 @@
+/// A feerate stored exactly in sats per 1000 vbytes.
+///
+/// Use the constructors to say which unit a rate is in. Fees are calculated with integer math and
+/// rounded up so paying [`fee_for_weight`] always achieves at least the feerate.
+///
+/// [`fee_for_weight`]: Self::fee_for_weight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct FeeRate {
+    sat_per_kvb: u64,
+}
+
+impl FeeRate {
+    /// A feerate of zero
+    pub const ZERO: FeeRate = FeeRate { sat_per_kvb: 0 };
+
+    /// The default minimum relay feerate of nodes (1 sat/vB)
+    pub const DEFAULT_MIN_RELAY: FeeRate = FeeRate { sat_per_kvb: 1_000 };
+
+    pub const fn from_sat_per_kvb(sat_per_kvb: u64) -> Self {
+        Self { sat_per_kvb }
+    }
+
+    /// Rounds to the nearest sat per 1000 vbytes. Negative rates are zero.
+    pub fn from_sat_per_vb(sat_per_vb: f32) -> Self {
+        Self::from_sat_per_kvb((sat_per_vb.max(0.0) * 1_000.0).round() as u64)
+    }
+
+    /// Creates a feerate from sats per 1000 weight units (a vbyte is four weight units).
+    pub const fn from_sat_per_kwu(sat_per_kwu: u64) -> Self {
+        Self::from_sat_per_kvb(sat_per_kwu.saturating_mul(4))
+    }
+
+    /// Creates a feerate from BTC per 1000 vbytes as returned by bitcoind's `estimatesmartfee`.
+    /// Rounds to the nearest sat per 1000 vbytes.
+    pub fn from_btc_per_kvb(btc_per_kvb: f64) -> Self {
+        Self::from_sat_per_kvb((btc_per_kvb.max(0.0) * 100_000_000.0).round() as u64)
+    }
+
+    /// The feerate a tx of `weight` paying `fee` has. Like a node this rounds the weight up to
+    /// whole vbytes and rounds the rate down.
+    pub fn from_fee_and_weight(fee: u64, weight: u32) -> Self {
+        let vbytes = div_round_up(weight as u128, 4);
+        match vbytes {
+            0 => Self::ZERO,
+            vbytes => {
+                Self::from_sat_per_kvb((fee as u128 * 1_000 / vbytes).min(u64::MAX as u128) as u64)
+            }
+        }
+    }
+
+    pub fn as_sat_per_kvb(&self) -> u64 {
+        self.sat_per_kvb
+    }
+
+    pub fn as_sat_per_vb(&self) -> f32 {
+        self.sat_per_kvb as f32 / 1_000.0
+    }
+
+    pub fn as_sat_per_wu(&self) -> f32 {
+        self.sat_per_kvb as f32 / 4_000.0
+    }
+
+    /// The fee needed for `weight` weight units at this feerate. Like a node this rounds the
+    /// weight up to whole vbytes and the fee up to the next sat.
+    pub fn fee_for_weight(&self, weight: u32) -> u64 {
+        let vbytes = div_round_up(weight as u128, 4);
+        let fee = div_round_up(self.sat_per_kvb as u128 * vbytes, 1_000);
+        fee.min(u64::MAX as u128) as u64
+    }
+
+    /// The fee needed for `vbytes` at this feerate rounded up to the next sat.
+    pub fn fee_for_vbytes(&self, vbytes: u32) -> u64 {
+        self.fee_for_weight(vbytes.saturating_mul(4))
+    }
+}
+
+fn div_round_up(numerator: u128, denominator: u128) -> u128 {
+    let quotient = numerator / denominator;
+    if quotient * denominator < numerator {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
+impl core::fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} sat/vB", self.as_sat_per_vb())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn units_convert_exactly() {
+        let one = FeeRate::from_sat_per_vb(1.0);
+        assert_eq!(one, FeeRate::DEFAULT_MIN_RELAY);
+        assert_eq!(one, FeeRate::from_sat_per_kwu(250));
+        assert_eq!(one, FeeRate::from_btc_per_kvb(0.00001));
+        assert_eq!(
+            FeeRate::from_btc_per_kvb(0.00123457).as_sat_per_kvb(),
+            123_457
+        );
+        assert_eq!(one.as_sat_per_wu(), 0.25);
+        assert_eq!(FeeRate::from_sat_per_vb(2.5).as_sat_per_kvb(), 2_500);
+        assert_eq!(FeeRate::from_sat_per_vb(-1.0), FeeRate::ZERO);
+        assert_eq!(
+            FeeRate::from_sat_per_kwu(u64::MAX).as_sat_per_kvb(),
+            u64::MAX
+        );
+
+        // a 1 sat/vB tx of 141 vbytes (561 wu rounds up) pays 141 sats
+        assert_eq!(one.fee_for_weight(561), 141);
+        assert_eq!(one.fee_for_weight(564), 141);
+        assert_eq!(one.fee_for_vbytes(141), 141);
+        assert_eq!(FeeRate::from_sat_per_vb(1.1).fee_for_weight(400), 110);
+        // 401 wu is 101 vbytes
+        let rate = FeeRate::from_sat_per_vb(1.1);
+        assert_eq!(rate.fee_for_weight(401), 112);
+        assert_eq!(rate.fee_for_weight(404), 112);
+        for weight in [1, 3, 401, 561, 1_001, 99_999] {
+            let fee = rate.fee_for_weight(weight);
+            assert!(FeeRate::from_fee_and_weight(fee, weight) >= rate);
+        }
+        assert_eq!(FeeRate::from_fee_and_weight(141, 561), one);
+        assert_eq!(FeeRate::from_fee_and_weight(1, 0), FeeRate::ZERO);
+    }
+}
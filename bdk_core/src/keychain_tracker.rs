@@ -0,0 +1,917 @@
+use crate::{
+    descriptor_tracker::{create_psbt, DerivedScripts},
+    multipath::split_multipath,
+    spk_tracker::{SpkTracker, UpdateCheck},
+    AncestorPackage, AugmentedTx, Balance, ChangeSet, CheckPoint, DerivationChanges,
+    DescriptorError, HashSet, HistoryEntry, LocalTxOut, MultipathError, PrimedInput, Update,
+    UpdateError, UpdateResult,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use bitcoin::{
+    psbt::PartiallySignedTransaction as Psbt,
+    secp256k1::{Secp256k1, Signing, VerifyOnly},
+    BlockHash, OutPoint, Script, TxOut, Txid,
+};
+use core::fmt::Debug;
+use miniscript::{
+    descriptor::{DerivedDescriptorKey, KeyMap},
+    Descriptor, DescriptorPublicKey,
+};
+
+/// The changes made to a [`KeychainTracker`] with the derivation changes of each keychain.
+pub type KeychainChangeSet<K> = ChangeSet<BTreeMap<K, DerivationChanges>>;
+
+/// An update for a [`KeychainTracker`] with the last active derivation index of each keychain.
+pub type KeychainUpdate<K> = Update<BTreeMap<K, u32>>;
+
+/// Tracks the txs of several descriptors (keychains) at once.
+///
+/// Unlike a set of [`DescriptorTracker`]s the keychains share a single view of the chain so a
+/// wallet only needs to sync and store it once. A tx that involves more than one keychain (e.g.
+/// one that spends from the external keychain and pays change to the internal one) is only stored
+/// once.
+///
+/// `K` identifies a keychain. It is typically an enum like `enum Keychain { External, Internal }`.
+///
+/// [`DescriptorTracker`]: crate::DescriptorTracker
+#[derive(Clone, Debug)]
+pub struct KeychainTracker<K> {
+    keychains: BTreeMap<K, DerivedScripts>,
+    graph: SpkTracker<(K, u32)>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl<K: Ord + Clone + Debug> KeychainTracker<K> {
+    /// Creates a tracker for `keychains`.
+    ///
+    /// Fails with the first keychain whose descriptor can't be tracked (see
+    /// [`DescriptorTracker::new`]).
+    ///
+    /// [`DescriptorTracker::new`]: crate::DescriptorTracker::new
+    pub fn new(
+        keychains: impl IntoIterator<Item = (K, Descriptor<DescriptorPublicKey>)>,
+    ) -> Result<Self, (K, DescriptorError)> {
+        let secp = Secp256k1::verification_only();
+        let keychains = keychains
+            .into_iter()
+            .map(
+                |(keychain, descriptor)| match DerivedScripts::new(descriptor, &secp) {
+                    Ok(derivation) => Ok((keychain, derivation)),
+                    Err(e) => Err((keychain, e)),
+                },
+            )
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            keychains,
+            graph: Default::default(),
+            secp,
+        })
+    }
+
+    /// Creates a tracker with the `external` and `internal` keychains of a multipath descriptor
+    /// like `wpkh(xpub/<0;1>/*)` (see [`split_multipath`]).
+    ///
+    /// Also returns the secret keys of both descriptors if it had any.
+    ///
+    /// [`split_multipath`]: crate::split_multipath
+    pub fn from_multipath<C: Signing>(
+        secp: &Secp256k1<C>,
+        descriptor: &str,
+        external: K,
+        internal: K,
+    ) -> Result<(Self, KeyMap), MultipathError> {
+        if external == internal {
+            return Err(MultipathError::SameKeychain);
+        }
+        let (external_descriptor, internal_descriptor) = split_multipath(descriptor)?;
+        let (external_descriptor, mut keymap) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(secp, &external_descriptor)?;
+        let (internal_descriptor, internal_keymap) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(secp, &internal_descriptor)?;
+        keymap.extend(internal_keymap);
+
+        let tracker = Self::new([
+            (external, external_descriptor),
+            (internal, internal_descriptor),
+        ])
+        .map_err(|(_, e)| e)?;
+        Ok((tracker, keymap))
+    }
+
+    /// Iterates over the keychains and their descriptors.
+    pub fn keychains(&self) -> impl Iterator<Item = (&K, &Descriptor<DescriptorPublicKey>)> {
+        self.keychains
+            .iter()
+            .map(|(keychain, derivation)| (keychain, derivation.descriptor()))
+    }
+
+    pub fn descriptor(&self, keychain: &K) -> Option<&Descriptor<DescriptorPublicKey>> {
+        self.keychains
+            .get(keychain)
+            .map(|derivation| derivation.descriptor())
+    }
+
+    pub fn next_derivation_index(&self, keychain: &K) -> Option<u32> {
+        self.keychains
+            .get(keychain)
+            .map(|derivation| derivation.next_derivation_index())
+    }
+
+    pub fn latest_blockheight(&self) -> Option<u32> {
+        self.graph.latest_blockheight()
+    }
+
+    pub fn latest_checkpoint(&self) -> Option<CheckPoint> {
+        self.graph.latest_checkpoint()
+    }
+
+    pub fn checkpoint_at(&self, height: u32) -> Option<CheckPoint> {
+        self.graph.checkpoint_at(height)
+    }
+
+    /// A commitment to all the txids confirmed up to and including the checkpoint at `height`.
+    ///
+    /// See [`DescriptorTracker::checkpoint_commitment`](crate::DescriptorTracker::checkpoint_commitment).
+    pub fn checkpoint_commitment(&self, height: u32) -> Option<[u8; 32]> {
+        self.graph.checkpoint_commitment(height)
+    }
+
+    pub fn iter_checkpoints(&self) -> impl Iterator<Item = (CheckPoint, &HashSet<Txid>)> {
+        self.graph.iter_checkpoints()
+    }
+
+    /// Checkpoints to find where the tracker's view of the chain forks from the current one.
+    ///
+    /// See [`DescriptorTracker::block_locator`](crate::DescriptorTracker::block_locator).
+    pub fn block_locator(&self) -> Vec<CheckPoint> {
+        self.graph.block_locator()
+    }
+
+    /// Thins out old checkpoints keeping the latest `keep_recent` ones.
+    ///
+    /// See [`DescriptorTracker::prune_checkpoints`](crate::DescriptorTracker::prune_checkpoints).
+    pub fn prune_checkpoints(&mut self, keep_recent: usize) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        self.graph.prune_checkpoints(keep_recent, &mut changeset);
+        changeset
+    }
+
+    /// Applies an update to all the keychains at once.
+    ///
+    /// Keychains that are in `update.last_active_index` but not in the tracker are ignored.
+    pub fn apply_update(
+        &mut self,
+        update: KeychainUpdate<K>,
+    ) -> Result<UpdateResult<BTreeMap<K, DerivationChanges>>, UpdateError> {
+        let invalidate_from = match self.graph.check_update(&update)? {
+            UpdateCheck::Stale => return Ok(UpdateResult::Stale),
+            UpdateCheck::Consistent { invalidate_from } => invalidate_from,
+        };
+
+        let mut changeset = KeychainChangeSet::default();
+        // Like for a `DescriptorTracker` we keep revealing scripts until the txs don't pay to any
+        // unrevealed ones.
+        let mut reveal_to = update.last_active_index.clone();
+        loop {
+            for (keychain, index) in reveal_to {
+                let derivation = match self.keychains.get_mut(&keychain) {
+                    Some(derivation) => derivation,
+                    None => continue,
+                };
+                let graph = &mut self.graph;
+                let changes = derivation.reveal_to(index, &self.secp, |index, script| {
+                    graph.add_script(script, (keychain.clone(), index))
+                });
+                changeset
+                    .scripts
+                    .entry(keychain)
+                    .or_default()
+                    .append(changes);
+            }
+            reveal_to = self.unrevealed_indexes_paid_by(&update);
+            if reveal_to.is_empty() {
+                break;
+            }
+        }
+        changeset
+            .scripts
+            .retain(|_, changes| *changes != DerivationChanges::default());
+
+        self.graph
+            .apply_checked_update(update, invalidate_from, &mut changeset);
+
+        Ok(UpdateResult::Ok(changeset))
+    }
+
+    /// The highest index of a stored script pubkey at or past the next derivation index that a tx
+    /// in `update` pays to for each keychain.
+    fn unrevealed_indexes_paid_by(&self, update: &KeychainUpdate<K>) -> BTreeMap<K, u32> {
+        let mut unrevealed = BTreeMap::new();
+        for (keychain, index) in self.graph.indexes_paid_by(update) {
+            if Some(*index) < self.next_derivation_index(keychain) {
+                continue;
+            }
+            let highest = unrevealed.entry(keychain.clone()).or_insert(*index);
+            *highest = (*highest).max(*index);
+        }
+        unrevealed
+    }
+
+    pub fn lookahead(&self, keychain: &K) -> Option<u32> {
+        self.keychains
+            .get(keychain)
+            .map(|derivation| derivation.lookahead())
+    }
+
+    /// Sets how many script pubkeys past the next derivation index of `keychain` the tracker keeps
+    /// stored. See [`DescriptorTracker::set_lookahead`].
+    ///
+    /// [`DescriptorTracker::set_lookahead`]: crate::DescriptorTracker::set_lookahead
+    pub fn set_lookahead(&mut self, keychain: &K, lookahead: u32) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        if let Some(derivation) = self.keychains.get_mut(keychain) {
+            derivation.set_lookahead(lookahead);
+            let graph = &mut self.graph;
+            let last_stored_index = derivation.store_lookahead(&self.secp, |index, script| {
+                graph.add_script(script, (keychain.clone(), index))
+            });
+            changeset.scripts.insert(
+                keychain.clone(),
+                DerivationChanges {
+                    last_stored_index,
+                    lookahead: Some(lookahead),
+                    ..Default::default()
+                },
+            );
+        }
+        changeset
+    }
+
+    /// Applies a [`KeychainChangeSet`] that was produced by a tracker with the same keychains when
+    /// it was in the same state as this one.
+    pub fn apply_changeset(&mut self, changeset: KeychainChangeSet<K>) {
+        for (keychain, changes) in &changeset.scripts {
+            if let Some(derivation) = self.keychains.get_mut(keychain) {
+                let graph = &mut self.graph;
+                derivation.apply_changes(*changes, &self.secp, |index, script| {
+                    graph.add_script(script, (keychain.clone(), index))
+                });
+            }
+        }
+        self.graph.apply_changeset(changeset);
+    }
+
+    /// Returns a [`KeychainChangeSet`] which recreates the current state of the tracker when
+    /// applied to a new tracker with the same keychains.
+    pub fn full_changeset(&self) -> KeychainChangeSet<K> {
+        ChangeSet {
+            scripts: self
+                .keychains
+                .iter()
+                .map(|(keychain, derivation)| (keychain.clone(), derivation.full_changes()))
+                .collect(),
+            ..self.graph.full_changeset()
+        }
+    }
+
+    pub fn clear_mempool(&mut self) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        self.graph.clear_mempool(&mut changeset);
+        changeset
+    }
+
+    /// Removes the unconfirmed txs that were last seen before the unix timestamp `timestamp`. Use
+    /// this to expire txs when your updates don't have `mempool_is_total_set`.
+    pub fn prune_mempool_older_than(&mut self, timestamp: u64) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        self.graph
+            .prune_mempool_older_than(timestamp, &mut changeset);
+        changeset
+    }
+
+    /// See [`DescriptorTracker::prune_evicted`](crate::DescriptorTracker::prune_evicted).
+    pub fn prune_evicted(&mut self, confirmations: u32) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        self.graph.prune_evicted(confirmations, &mut changeset);
+        changeset
+    }
+
+    pub fn disconnect_block(
+        &mut self,
+        block_height: u32,
+        block_header: BlockHash,
+    ) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        self.graph
+            .disconnect_block(block_height, block_header, &mut changeset);
+        changeset
+    }
+
+    pub fn iter_tx(&self) -> impl Iterator<Item = (Txid, &AugmentedTx)> {
+        self.graph.iter_tx()
+    }
+
+    pub fn get_tx(&self, txid: Txid) -> Option<&AugmentedTx> {
+        self.graph.get_tx(txid)
+    }
+
+    /// The balance of all the keychains. Unconfirmed txouts of the keychains that `should_trust`
+    /// returns true for (e.g. the change keychain) count as trusted.
+    pub fn balance(&self, mut should_trust: impl FnMut(&K) -> bool) -> Balance {
+        self.graph.balance(|(keychain, _)| should_trust(keychain))
+    }
+
+    /// See [`DescriptorTracker::history`](crate::DescriptorTracker::history).
+    pub fn history(&self, offset: usize, limit: usize) -> Vec<HistoryEntry> {
+        self.graph.history(offset, limit)
+    }
+
+    /// Iterates over the unspent txouts of all keychains along with the keychain they belong to.
+    pub fn iter_unspent(&self) -> impl Iterator<Item = (K, LocalTxOut)> + '_ {
+        self.graph
+            .iter_unspent()
+            .map(|((keychain, _), txout)| (keychain.clone(), txout))
+    }
+
+    /// Iterates over the unspent txouts of all keychains that a tx confirmed at `height` could
+    /// spend i.e. without the coinbase outputs that haven't matured by then.
+    pub fn iter_spendable_unspent(
+        &self,
+        height: u32,
+    ) -> impl Iterator<Item = (K, LocalTxOut)> + '_ {
+        self.graph
+            .iter_spendable_unspent(height)
+            .map(|((keychain, _), txout)| (keychain.clone(), txout))
+    }
+
+    /// Iterates over the txouts of all keychains along with the keychain they belong to.
+    pub fn iter_txout(&self) -> impl Iterator<Item = (K, LocalTxOut)> + '_ {
+        self.graph
+            .iter_txout()
+            .map(|((keychain, _), txout)| (keychain.clone(), txout))
+    }
+
+    pub fn get_txout(&self, txo: OutPoint) -> Option<(K, LocalTxOut)> {
+        self.graph
+            .get_txout(txo)
+            .map(|((keychain, _), txout)| (keychain.clone(), txout))
+    }
+
+    /// The tracked txs that `txid` spends from, directly or through other tracked txs.
+    pub fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.ancestors(txid)
+    }
+
+    /// The tracked txs that spend from `txid`, directly or through other tracked txs.
+    pub fn descendants(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.descendants(txid)
+    }
+
+    /// The unconfirmed tx `txid` together with its unconfirmed ancestors along with their total
+    /// weight and fee. This is what a CPFP tx spending from `txid` has to pay for.
+    ///
+    /// Returns `None` if `txid` isn't tracked or is confirmed.
+    pub fn unconfirmed_ancestor_package(&self, txid: Txid) -> Option<AncestorPackage> {
+        self.graph.unconfirmed_ancestor_package(txid)
+    }
+
+    /// The txids of the unconfirmed txs with every tx after its parents. Txs seen first go first
+    /// where possible.
+    pub fn mempool_topological_order(&self) -> Vec<Txid> {
+        self.graph.mempool_topological_order()
+    }
+
+    /// Returns the txid of the tx that replaced `txid` if it was evicted from the tracker because
+    /// it conflicted with another tx.
+    pub fn replaced_by(&self, txid: Txid) -> Option<Txid> {
+        self.graph.replaced_by(txid)
+    }
+
+    pub fn iter_evicted(&self) -> impl Iterator<Item = (Txid, Txid)> + '_ {
+        self.graph.iter_evicted()
+    }
+
+    pub fn iter_spends(&self, outpoint: OutPoint) -> impl Iterator<Item = (u32, Txid)> + '_ {
+        self.graph.iter_spends(outpoint)
+    }
+
+    /// Iterates over the script pubkeys of each keychain for syncing.
+    ///
+    /// **WARNING**: never turn these into addresses or send coins to them. Use
+    /// [`derive_new`](Self::derive_new) for that.
+    pub fn iter_scripts(&self) -> BTreeMap<K, impl Iterator<Item = (u32, Script)>> {
+        self.keychains
+            .iter()
+            .map(|(keychain, derivation)| {
+                (
+                    keychain.clone(),
+                    derivation
+                        .iter_scripts(&self.secp)
+                        .enumerate()
+                        .map(|(i, script)| (i as u32, script)),
+                )
+            })
+            .collect()
+    }
+
+    pub fn script_at_index(&self, keychain: &K, index: u32) -> Option<&Script> {
+        self.keychains.get(keychain)?.script_at_index(index)
+    }
+
+    /// Derives a new script pubkey for `keychain` which can be turned into an address.
+    ///
+    /// # Panics
+    ///
+    /// If the tracker doesn't have `keychain`.
+    pub fn derive_new(&mut self, keychain: &K) -> ((u32, &Script), KeychainChangeSet<K>) {
+        let derivation = self
+            .keychains
+            .get_mut(keychain)
+            .expect("the keychain must exist");
+        let index = derivation.index_to_reserve();
+        let mut changes = DerivationChanges {
+            last_stored_index: store_scripts(
+                &mut self.graph,
+                keychain,
+                derivation,
+                index,
+                &self.secp,
+            ),
+            next_derivation_index: derivation.reserve_next_index(),
+            ..Default::default()
+        };
+        let graph = &mut self.graph;
+        changes.append(DerivationChanges {
+            last_stored_index: derivation.store_lookahead(&self.secp, |index, script| {
+                graph.add_script(script, (keychain.clone(), index))
+            }),
+            ..Default::default()
+        });
+        let mut changeset = KeychainChangeSet::default();
+        if changes != DerivationChanges::default() {
+            changeset.scripts.insert(keychain.clone(), changes);
+        }
+        let script = self.keychains[keychain]
+            .script_at_index(index)
+            .expect("we just derived to that index");
+        ((index, script), changeset)
+    }
+
+    /// Derives a new script pubkey for `keychain` only if it doesn't have one that hasn't been
+    /// used.
+    ///
+    /// # Panics
+    ///
+    /// If the tracker doesn't have `keychain`.
+    pub fn derive_next_unused(&mut self, keychain: &K) -> ((u32, &Script), KeychainChangeSet<K>) {
+        let need_new = self.iter_unused_derived_scripts(keychain).next().is_none();
+        // this rather strange branch is needed because of some lifetime issues
+        if need_new {
+            self.derive_new(keychain)
+        } else {
+            (
+                self.iter_unused_derived_scripts(keychain).next().unwrap(),
+                KeychainChangeSet::default(),
+            )
+        }
+    }
+
+    pub fn iter_derived_scripts(&self, keychain: &K) -> impl Iterator<Item = &Script> {
+        self.keychains
+            .get(keychain)
+            .into_iter()
+            .flat_map(|derivation| derivation.iter_derived_scripts())
+    }
+
+    pub fn iter_unused_derived_scripts(
+        &self,
+        keychain: &K,
+    ) -> impl Iterator<Item = (u32, &Script)> {
+        let keychain = keychain.clone();
+        self.iter_derived_scripts(&keychain)
+            .enumerate()
+            .filter(move |(i, _)| !self.is_used(&keychain, *i as u32))
+            .map(|(index, script)| (index as u32, script))
+    }
+
+    pub fn is_used(&self, keychain: &K, index: u32) -> bool {
+        self.graph.is_used(&(keychain.clone(), index))
+    }
+
+    /// Stores the script pubkeys of `keychain` up to and including the one at `end` so that the
+    /// tracker will recognise txouts that pay to them.
+    pub fn store_scripts(&mut self, keychain: &K, end: u32) -> KeychainChangeSet<K> {
+        let mut changeset = KeychainChangeSet::default();
+        if let Some(derivation) = self.keychains.get_mut(keychain) {
+            let last_stored_index =
+                store_scripts(&mut self.graph, keychain, derivation, end, &self.secp);
+            if last_stored_index.is_some() {
+                changeset.scripts.insert(
+                    keychain.clone(),
+                    DerivationChanges {
+                        last_stored_index,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        changeset
+    }
+
+    /// Returns the keychain and derivation index a script pubkey was derived at.
+    pub fn index_of_stored_script(&self, script: &Script) -> Option<(K, u32)> {
+        self.graph.index_of_script(script).cloned()
+    }
+
+    pub fn max_satisfaction_weight(&self, keychain: &K) -> Option<u32> {
+        Some(self.keychains.get(keychain)?.max_satisfaction_weight())
+    }
+
+    pub fn dust_value(&self, keychain: &K) -> Option<u64> {
+        Some(self.keychains.get(keychain)?.dust_value())
+    }
+
+    /// Prepare an input for insertion into a PSBT
+    pub fn prime_input(&self, op: OutPoint) -> Option<PrimedInput> {
+        let ((keychain, index), _) = self.graph.get_txout(op)?;
+        let prev_tx = self
+            .graph
+            .get_tx(op.txid)
+            .expect("since the txout exists so must the transaction");
+        Some(self.keychains[keychain].prime_input(&prev_tx.tx, op.vout, *index))
+    }
+
+    pub fn create_psbt<I, O>(
+        &self,
+        inputs: I,
+        outputs: O,
+    ) -> (Psbt, BTreeMap<usize, Descriptor<DerivedDescriptorKey>>)
+    where
+        I: IntoIterator<Item = OutPoint>,
+        O: IntoIterator<Item = TxOut>,
+    {
+        create_psbt(inputs, outputs, |outpoint| self.prime_input(outpoint))
+    }
+}
+
+fn store_scripts<K: Ord + Clone + Debug>(
+    graph: &mut SpkTracker<(K, u32)>,
+    keychain: &K,
+    derivation: &mut DerivedScripts,
+    end: u32,
+    secp: &Secp256k1<VerifyOnly>,
+) -> Option<u32> {
+    derivation.store_scripts(end, secp, |index, script| {
+        graph.add_script(script, (keychain.clone(), index))
+    })
+}
+
+/// The serialized form of a [`KeychainTracker`].
+///
+/// Like a serialized [`DescriptorTracker`] only the descriptors and the state needed to recreate
+/// the tracker is serialized.
+///
+/// [`DescriptorTracker`]: crate::DescriptorTracker
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(crate = "serde_crate")]
+struct SerdeKeychainTracker<K: Ord> {
+    keychains: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    state: KeychainChangeSet<K>,
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone + Debug + serde::Serialize> serde::Serialize for KeychainTracker<K> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerdeKeychainTracker {
+            keychains: self
+                .keychains()
+                .map(|(keychain, descriptor)| (keychain.clone(), descriptor.clone()))
+                .collect(),
+            state: self.full_changeset(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K: Ord + Clone + Debug + serde::Deserialize<'de>> serde::Deserialize<'de>
+    for KeychainTracker<K>
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SerdeKeychainTracker { keychains, state } =
+            SerdeKeychainTracker::deserialize(deserializer)?;
+        let mut tracker = KeychainTracker::new(keychains).map_err(|(keychain, e)| {
+            serde::de::Error::custom(format_args!("{:?}: {}", keychain, e))
+        })?;
+        tracker.apply_changeset(state);
+        Ok(tracker)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{testutils::*, Amount, Balance, BlockTime, PrevOuts};
+    use alloc::vec::Vec;
+
+    const EXTERNAL: &str = DERIVABLE_DESCRIPTOR;
+    const INTERNAL: &str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/1/*)";
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(serde::Deserialize, serde::Serialize),
+        serde(crate = "serde_crate")
+    )]
+    enum Keychain {
+        External,
+        Internal,
+    }
+
+    fn new_tracker() -> KeychainTracker<Keychain> {
+        KeychainTracker::new([
+            (Keychain::External, EXTERNAL.parse().unwrap()),
+            (Keychain::Internal, INTERNAL.parse().unwrap()),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn one_update_for_all_keychains() {
+        let mut tracker = new_tracker();
+        let ((_, receive), _) = tracker.derive_new(&Keychain::External);
+        let receive = receive.clone();
+        let change = tracker
+            .iter_scripts()
+            .remove(&Keychain::Internal)
+            .unwrap()
+            .nth(2)
+            .unwrap()
+            .1;
+
+        let incoming = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![TxOut {
+                value: 10_000,
+                script_pubkey: receive,
+            }],
+        );
+        let outgoing = spending_tx(
+            &[OutPoint::new(incoming.txid(), 0)],
+            vec![
+                TxOut {
+                    value: 5_000,
+                    script_pubkey: Script::default(),
+                },
+                TxOut {
+                    value: 4_000,
+                    script_pubkey: change.clone(),
+                },
+            ],
+        );
+
+        let update = KeychainUpdate {
+            transactions: vec![
+                (
+                    PrevOuts::Spend(vec![TxOut::default()]),
+                    incoming.clone(),
+                    Some(BlockTime { height: 1, time: 1 }),
+                    None,
+                ),
+                (
+                    PrevOuts::Spend(vec![incoming.output[0].clone()]),
+                    outgoing.clone(),
+                    None,
+                    None,
+                ),
+            ],
+            mempool_is_total_set: true,
+            last_active_index: [(Keychain::External, 0), (Keychain::Internal, 2)].into(),
+            base_tip: None,
+            invalidate: Vec::new(),
+            new_tip: CheckPoint {
+                height: 1,
+                hash: BlockHash::default(),
+            },
+        };
+        let changeset = match tracker.apply_update(update) {
+            Ok(UpdateResult::Ok(changeset)) => changeset,
+            res => panic!("unexpected update result {:?}", res),
+        };
+
+        // the tx that touches both keychains is only stored once
+        assert_eq!(tracker.iter_tx().count(), 2);
+        assert_eq!(tracker.get_tx(outgoing.txid()).unwrap().fee, 1_000);
+        assert_eq!(
+            tracker
+                .iter_unspent()
+                .map(|(keychain, txout)| (keychain, txout.derivation_index, txout.value))
+                .collect::<Vec<_>>(),
+            vec![(Keychain::Internal, 2, 4_000)]
+        );
+        assert_eq!(
+            tracker
+                .get_txout(OutPoint {
+                    txid: incoming.txid(),
+                    vout: 0
+                })
+                .unwrap()
+                .0,
+            Keychain::External
+        );
+        assert_eq!(
+            tracker.index_of_stored_script(&change),
+            Some((Keychain::Internal, 2))
+        );
+
+        // the derivation index of each keychain moves independently
+        assert_eq!(tracker.next_derivation_index(&Keychain::External), Some(1));
+        assert_eq!(tracker.next_derivation_index(&Keychain::Internal), Some(3));
+        assert_eq!(
+            changeset.scripts[&Keychain::Internal],
+            DerivationChanges {
+                next_derivation_index: Some(3),
+                last_stored_index: Some(2),
+                lookahead: None,
+            }
+        );
+        assert_eq!(tracker.derive_next_unused(&Keychain::External).0 .0, 1);
+        assert_eq!(tracker.derive_next_unused(&Keychain::Internal).0 .0, 0);
+
+        tracker.set_lookahead(&Keychain::Internal, 4);
+        let mut restored = new_tracker();
+        restored.apply_changeset(tracker.full_changeset());
+        assert_eq!(
+            restored.iter_txout().collect::<Vec<_>>(),
+            tracker.iter_txout().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            restored.next_derivation_index(&Keychain::External),
+            tracker.next_derivation_index(&Keychain::External)
+        );
+        assert_eq!(restored.lookahead(&Keychain::Internal), Some(4));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&tracker).unwrap();
+            let deserialized: KeychainTracker<Keychain> = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized.full_changeset(), tracker.full_changeset());
+            assert_eq!(
+                deserialized.descriptor(&Keychain::Internal),
+                tracker.descriptor(&Keychain::Internal)
+            );
+        }
+    }
+
+    #[test]
+    fn balance_by_keychain_and_maturity() {
+        let mut tracker = new_tracker();
+        let receive = tracker.derive_new(&Keychain::External).0 .1.clone();
+        let change = tracker.derive_new(&Keychain::Internal).0 .1.clone();
+        let pay = |value, script_pubkey: &Script, vout| {
+            spending_tx(
+                &[OutPoint::new(Txid::default(), vout)],
+                vec![TxOut {
+                    value,
+                    script_pubkey: script_pubkey.clone(),
+                }],
+            )
+        };
+
+        let coinbase = spending_tx(&[OutPoint::null()], pay(50_000, &receive, 0).output);
+        let transactions = vec![
+            (
+                PrevOuts::Coinbase,
+                coinbase,
+                Some(BlockTime { height: 1, time: 1 }),
+                None,
+            ),
+            (
+                PrevOuts::Spend(vec![TxOut::default()]),
+                pay(1_000, &receive, 1),
+                Some(BlockTime { height: 2, time: 2 }),
+                None,
+            ),
+            (
+                PrevOuts::Spend(vec![TxOut::default()]),
+                pay(200, &receive, 2),
+                None,
+                None,
+            ),
+            (
+                PrevOuts::Spend(vec![TxOut::default()]),
+                pay(30, &change, 3),
+                None,
+                None,
+            ),
+        ];
+        let mut update = KeychainUpdate {
+            transactions,
+            mempool_is_total_set: false,
+            last_active_index: Default::default(),
+            base_tip: None,
+            invalidate: Vec::new(),
+            new_tip: CheckPoint {
+                height: 99,
+                hash: BlockHash::default(),
+            },
+        };
+        assert!(matches!(
+            tracker.apply_update(update.clone()),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let balance = tracker.balance(|keychain| *keychain == Keychain::Internal);
+        assert_eq!(
+            balance,
+            Balance {
+                immature: Amount::from_sat(50_000),
+                trusted_pending: Amount::from_sat(30),
+                untrusted_pending: Amount::from_sat(200),
+                confirmed: Amount::from_sat(1_000),
+            }
+        );
+        assert_eq!(balance.total(), Amount::from_sat(51_230));
+        assert_eq!(balance.trusted_spendable(), Amount::from_sat(1_030));
+
+        // a tx spending the coinbase output can be in the next block
+        update.transactions.clear();
+        update.base_tip = tracker.latest_checkpoint();
+        update.new_tip.height = 100;
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+        let balance = tracker.balance(|_| false);
+        assert_eq!(balance.immature, Amount::ZERO);
+        assert_eq!(balance.confirmed, Amount::from_sat(51_000));
+        assert_eq!(balance.untrusted_pending, Amount::from_sat(230));
+    }
+
+    #[test]
+    fn multipath_descriptor_creates_both_keychains() {
+        let multipath = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/<0;1>/*)";
+        let (tracker, keymap) = KeychainTracker::from_multipath(
+            &Secp256k1::signing_only(),
+            multipath,
+            Keychain::External,
+            Keychain::Internal,
+        )
+        .unwrap();
+        assert!(keymap.is_empty());
+        assert_eq!(
+            tracker.descriptor(&Keychain::External),
+            Some(&EXTERNAL.parse().unwrap())
+        );
+        assert_eq!(
+            tracker.descriptor(&Keychain::Internal),
+            Some(&INTERNAL.parse().unwrap())
+        );
+
+        assert!(matches!(
+            KeychainTracker::from_multipath(
+                &Secp256k1::signing_only(),
+                EXTERNAL,
+                Keychain::External,
+                Keychain::Internal
+            ),
+            Err(MultipathError::NotMultipath)
+        ));
+        assert!(matches!(
+            KeychainTracker::from_multipath(
+                &Secp256k1::signing_only(),
+                multipath,
+                Keychain::External,
+                Keychain::External
+            ),
+            Err(MultipathError::SameKeychain)
+        ));
+    }
+
+    #[test]
+    fn invalid_keychain_descriptor_is_reported() {
+        let xpub = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+        let hardened = alloc::format!("wpkh({}/1'/*)", xpub);
+        assert_eq!(
+            KeychainTracker::new([
+                (Keychain::External, EXTERNAL.parse().unwrap()),
+                (Keychain::Internal, hardened.parse().unwrap()),
+            ])
+            .err(),
+            Some((Keychain::Internal, DescriptorError::HardenedDerivation))
+        );
+
+        assert!(matches!(
+            KeychainTracker::from_multipath(
+                &Secp256k1::signing_only(),
+                &alloc::format!("wpkh({}/<0;1>/*')", xpub),
+                Keychain::External,
+                Keychain::Internal
+            ),
+            Err(MultipathError::Descriptor(
+                DescriptorError::HardenedWildcard
+            ))
+        ));
+    }
+}
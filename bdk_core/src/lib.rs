@@ -2,12 +2,24 @@
 use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 pub use bitcoin;
+pub use bitcoin::{Amount, SignedAmount};
 use bitcoin::{BlockHash, TxOut};
 pub use miniscript;
 mod descriptor_tracker;
+mod spk_tracker;
 pub use descriptor_tracker::*;
+mod keychain_tracker;
+pub use keychain_tracker::*;
+mod script_tracker;
+pub use script_tracker::*;
+mod multipath;
+pub use multipath::*;
+mod feerate;
+pub use feerate::*;
 pub mod coin_select;
 pub mod sign;
+#[cfg(test)]
+mod testutils;
 
 #[allow(unused_imports)]
 extern crate alloc;
@@ -50,7 +62,77 @@ type HashMap<K, V> = BTreeMap<K, V>;
 type HashSet<K> = BTreeSet<K>;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub enum PrevOuts {
     Coinbase,
     Spend(Vec<TxOut>),
 }
+
+/// The number of blocks a coinbase output has to be buried under before it can be spent.
+///
+/// A tx spending a coinbase output can be included in the block at `confirmation height +
+/// COINBASE_MATURITY` at the earliest.
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// The balance of a tracker split up by how spendable it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Balance {
+    /// Coinbase outputs that haven't reached [`COINBASE_MATURITY`] yet
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub immature: Amount,
+    /// Unconfirmed outputs that belong to a trusted keychain (e.g. our own change)
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub trusted_pending: Amount,
+    /// Unconfirmed outputs that belong to an untrusted keychain (e.g. incoming payments)
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub untrusted_pending: Amount,
+    /// Confirmed outputs that can be spent
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "bitcoin::util::amount::serde::as_sat")
+    )]
+    pub confirmed: Amount,
+}
+
+impl Balance {
+    /// The amount we can spend without relying on someone else's unconfirmed tx
+    pub fn trusted_spendable(&self) -> Amount {
+        self.confirmed + self.trusted_pending
+    }
+
+    /// The sum of all the amounts
+    pub fn total(&self) -> Amount {
+        self.immature + self.trusted_pending + self.untrusted_pending + self.confirmed
+    }
+}
+
+impl core::ops::Add for Balance {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            immature: self.immature + other.immature,
+            trusted_pending: self.trusted_pending + other.trusted_pending,
+            untrusted_pending: self.untrusted_pending + other.untrusted_pending,
+            confirmed: self.confirmed + other.confirmed,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use alloc::{string::String, vec::Vec};
+use miniscript::descriptor::desc_checksum;
+
+use crate::DescriptorError;
+
+/// A multipath descriptor (e.g. `wpkh(xpub/<0;1>/*)`) that can't be split into an external and an
+/// internal descriptor.
+#[derive(Debug)]
+pub enum MultipathError {
+    /// The descriptor doesn't have any `<a;b>` derivation steps
+    NotMultipath,
+    /// A `<...>` derivation step doesn't have exactly two distinct paths or isn't part of a key's
+    /// derivation path
+    InvalidStep(String),
+    /// The checksum after the `#` doesn't match the descriptor
+    InvalidChecksum,
+    /// One of the single path descriptors couldn't be parsed
+    Parse(miniscript::Error),
+    /// The external and internal keychains are the same so one would replace the other
+    SameKeychain,
+    /// The descriptors can't be tracked
+    Descriptor(DescriptorError),
+}
+
+impl core::fmt::Display for MultipathError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MultipathError::NotMultipath => {
+                write!(f, "the descriptor has no <a;b> derivation step")
+            }
+            MultipathError::InvalidStep(step) => write!(
+                f,
+                "the derivation step {} must be in a key path and have two distinct paths",
+                step
+            ),
+            MultipathError::InvalidChecksum => write!(f, "the descriptor checksum is invalid"),
+            MultipathError::Parse(e) => write!(f, "{}", e),
+            MultipathError::SameKeychain => {
+                write!(f, "the external and internal keychains must be different")
+            }
+            MultipathError::Descriptor(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<miniscript::Error> for MultipathError {
+    fn from(e: miniscript::Error) -> Self {
+        MultipathError::Parse(e)
+    }
+}
+
+impl From<DescriptorError> for MultipathError {
+    fn from(e: DescriptorError) -> Self {
+        MultipathError::Descriptor(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MultipathError {}
+
+/// Splits a multipath descriptor like `wpkh([origin]xpub/<0;1>/*)` into the external
+/// (`wpkh([origin]xpub/0/*)`) and internal (`wpkh([origin]xpub/1/*)`) descriptor strings.
+///
+/// Every `<a;b>` step must be a step in a key's derivation path with exactly two distinct paths.
+/// Since everything apart from these steps is shared the two descriptors always have the same
+/// keys. If the descriptor has a checksum it is checked and left out of the returned strings.
+pub fn split_multipath(descriptor: &str) -> Result<(String, String), MultipathError> {
+    let descriptor = match descriptor.split_once('#') {
+        Some((descriptor, checksum)) => {
+            if desc_checksum(descriptor)? != checksum {
+                return Err(MultipathError::InvalidChecksum);
+            }
+            descriptor
+        }
+        None => descriptor,
+    };
+
+    let mut external = String::with_capacity(descriptor.len());
+    let mut internal = String::with_capacity(descriptor.len());
+    let mut rest = descriptor;
+    let mut found = false;
+    while let Some(start) = rest.find('<') {
+        let (before, after) = rest.split_at(start);
+        let end = after
+            .find('>')
+            .ok_or_else(|| MultipathError::InvalidStep(after.into()))?;
+        let step = &after[..=end];
+        let paths = step[1..step.len() - 1].split(';').collect::<Vec<_>>();
+        let in_key_path = before.ends_with('/')
+            && matches!(after[end + 1..].chars().next(), Some('/' | ')' | ','));
+        if !in_key_path
+            || paths.len() != 2
+            || paths.iter().any(|path| path.is_empty())
+            || paths[0] == paths[1]
+        {
+            return Err(MultipathError::InvalidStep(step.into()));
+        }
+
+        external.push_str(before);
+        external.push_str(paths[0]);
+        internal.push_str(before);
+        internal.push_str(paths[1]);
+        rest = &after[end + 1..];
+        found = true;
+    }
+
+    if !found {
+        return Err(MultipathError::NotMultipath);
+    }
+    external.push_str(rest);
+    internal.push_str(rest);
+    Ok((external, internal))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const XPUB: &str = "[73c5da0a/86'/0'/0']xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+
+    #[test]
+    fn splits_multipath_steps() {
+        let multipath = alloc::format!("wpkh({}/<0;1>/*)", XPUB);
+        let (external, internal) = split_multipath(&multipath).unwrap();
+        assert_eq!(external, alloc::format!("wpkh({}/0/*)", XPUB));
+        assert_eq!(internal, alloc::format!("wpkh({}/1/*)", XPUB));
+
+        let with_checksum = alloc::format!("{}#{}", multipath, desc_checksum(&multipath).unwrap());
+        assert_eq!(
+            split_multipath(&with_checksum).unwrap(),
+            (external.clone(), internal)
+        );
+        assert!(matches!(
+            split_multipath(&alloc::format!("{}#qqqqqqqq", multipath)),
+            Err(MultipathError::InvalidChecksum)
+        ));
+
+        assert!(matches!(
+            split_multipath(&external),
+            Err(MultipathError::NotMultipath)
+        ));
+        for step in ["<0;1;2>", "<0;0>", "<0>", "<0;>", "<;1>", "<;>"] {
+            assert!(matches!(
+                split_multipath(&alloc::format!("wpkh({}/{}/*)", XPUB, step)),
+                Err(MultipathError::InvalidStep(_))
+            ));
+        }
+    }
+}
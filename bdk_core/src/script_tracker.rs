@@ -0,0 +1,414 @@
+use crate::{
+    spk_tracker::{SpkTracker, UpdateCheck},
+    AncestorPackage, AugmentedTx, Balance, ChangeSet, CheckPoint, HashSet, HistoryEntry,
+    LocalTxOut, Update, UpdateError, UpdateResult,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use bitcoin::{BlockHash, OutPoint, Script, Txid};
+
+/// The changes made to a [`ScriptTracker`] with the scripts and outpoints it started watching.
+pub type ScriptChangeSet = ChangeSet<ScriptChanges>;
+
+/// The script pubkeys and outpoints a [`ScriptTracker`] started watching by their index.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ScriptChanges {
+    pub scripts: BTreeMap<u32, Script>,
+    pub outpoints: BTreeMap<u32, OutPoint>,
+}
+
+/// Tracks the txs related to arbitrary script pubkeys and outpoints.
+///
+/// This is for things that aren't derived from a descriptor like someone else's deposit address or
+/// the funding output of a channel. It uses the same checkpoint, mempool and reorg logic as the
+/// [`DescriptorTracker`] and takes the same [`Update`].
+///
+/// Each script pubkey or outpoint gets an index in the order they were added. This is what's in
+/// [`LocalTxOut::derivation_index`] for the txouts of this tracker.
+///
+/// [`DescriptorTracker`]: crate::DescriptorTracker
+#[derive(Clone, Debug, Default)]
+pub struct ScriptTracker {
+    scripts: BTreeMap<u32, Script>,
+    outpoints: BTreeMap<u32, OutPoint>,
+    graph: SpkTracker<u32>,
+}
+
+impl ScriptTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_index(&self) -> u32 {
+        let last_script = self.scripts.keys().next_back();
+        let last_outpoint = self.outpoints.keys().next_back();
+        last_script
+            .max(last_outpoint)
+            .map(|last| last + 1)
+            .unwrap_or(0)
+    }
+
+    /// Starts watching for txouts that pay to `script`. Returns the index of the script.
+    pub fn add_script(&mut self, script: Script) -> (u32, ScriptChangeSet) {
+        let mut changeset = ScriptChangeSet::default();
+        if let Some(index) = self.graph.index_of_script(&script) {
+            return (*index, changeset);
+        }
+        let index = self.next_index();
+        self.scripts.insert(index, script.clone());
+        self.graph.add_script(script.clone(), index);
+        changeset.scripts.scripts.insert(index, script);
+        (index, changeset)
+    }
+
+    /// Starts watching the txout at `outpoint`. Returns the index of the outpoint.
+    ///
+    /// The tx that creates the outpoint has to be in an update to find it. Once it has been found
+    /// its script pubkey is included in [`iter_scripts`] so that txs spending it will be found too.
+    ///
+    /// [`iter_scripts`]: Self::iter_scripts
+    pub fn add_outpoint(&mut self, outpoint: OutPoint) -> (u32, ScriptChangeSet) {
+        let mut changeset = ScriptChangeSet::default();
+        if let Some((index, _)) = self.outpoints.iter().find(|(_, op)| **op == outpoint) {
+            return (*index, changeset);
+        }
+        let index = self.next_index();
+        self.outpoints.insert(index, outpoint);
+        self.graph.add_outpoint(outpoint, index);
+        changeset.scripts.outpoints.insert(index, outpoint);
+        (index, changeset)
+    }
+
+    /// Iterates over the script pubkeys to sync along with their index.
+    ///
+    /// This includes the script pubkeys of the watched outpoints that have been found. Pass it to a
+    /// blockchain client with a stop gap at least as large as the number of scripts since there is
+    /// no gap between the scripts here.
+    pub fn iter_scripts(&self) -> impl Iterator<Item = (u32, Script)> + '_ {
+        let outpoint_scripts = self.outpoints.iter().filter_map(|(index, outpoint)| {
+            let tx = self.graph.get_tx(outpoint.txid)?;
+            let txout = tx.tx.output.get(outpoint.vout as usize)?;
+            Some((*index, txout.script_pubkey.clone()))
+        });
+        self.scripts
+            .iter()
+            .map(|(index, script)| (*index, script.clone()))
+            .chain(outpoint_scripts)
+    }
+
+    pub fn iter_outpoints(&self) -> impl Iterator<Item = (u32, OutPoint)> + '_ {
+        self.outpoints
+            .iter()
+            .map(|(index, outpoint)| (*index, *outpoint))
+    }
+
+    pub fn index_of_script(&self, script: &Script) -> Option<u32> {
+        self.graph.index_of_script(script).cloned()
+    }
+
+    /// Whether any txouts have been found for the script or outpoint at `index`
+    pub fn is_used(&self, index: u32) -> bool {
+        self.graph.is_used(&index)
+    }
+
+    pub fn latest_blockheight(&self) -> Option<u32> {
+        self.graph.latest_blockheight()
+    }
+
+    pub fn latest_checkpoint(&self) -> Option<CheckPoint> {
+        self.graph.latest_checkpoint()
+    }
+
+    pub fn checkpoint_at(&self, height: u32) -> Option<CheckPoint> {
+        self.graph.checkpoint_at(height)
+    }
+
+    pub fn checkpoint_commitment(&self, height: u32) -> Option<[u8; 32]> {
+        self.graph.checkpoint_commitment(height)
+    }
+
+    pub fn iter_checkpoints(&self) -> impl Iterator<Item = (CheckPoint, &HashSet<Txid>)> {
+        self.graph.iter_checkpoints()
+    }
+
+    /// Checkpoints to find where the tracker's view of the chain forks from the current one.
+    ///
+    /// See [`DescriptorTracker::block_locator`](crate::DescriptorTracker::block_locator).
+    pub fn block_locator(&self) -> Vec<CheckPoint> {
+        self.graph.block_locator()
+    }
+
+    /// Thins out old checkpoints keeping the latest `keep_recent` ones.
+    ///
+    /// See [`DescriptorTracker::prune_checkpoints`](crate::DescriptorTracker::prune_checkpoints).
+    pub fn prune_checkpoints(&mut self, keep_recent: usize) -> ScriptChangeSet {
+        let mut changeset = ScriptChangeSet::default();
+        self.graph.prune_checkpoints(keep_recent, &mut changeset);
+        changeset
+    }
+
+    /// Applies an update. The update's `last_active_index` is ignored.
+    pub fn apply_update(
+        &mut self,
+        update: Update,
+    ) -> Result<UpdateResult<ScriptChanges>, UpdateError> {
+        let invalidate_from = match self.graph.check_update(&update)? {
+            UpdateCheck::Stale => return Ok(UpdateResult::Stale),
+            UpdateCheck::Consistent { invalidate_from } => invalidate_from,
+        };
+
+        let mut changeset = ScriptChangeSet::default();
+        self.graph
+            .apply_checked_update(update, invalidate_from, &mut changeset);
+
+        Ok(UpdateResult::Ok(changeset))
+    }
+
+    /// Applies a [`ScriptChangeSet`] that was produced by a tracker in the same state as this one.
+    pub fn apply_changeset(&mut self, changeset: ScriptChangeSet) {
+        for (index, script) in &changeset.scripts.scripts {
+            self.scripts.insert(*index, script.clone());
+            self.graph.add_script(script.clone(), *index);
+        }
+        for (index, outpoint) in &changeset.scripts.outpoints {
+            self.outpoints.insert(*index, *outpoint);
+            self.graph.add_outpoint(*outpoint, *index);
+        }
+        self.graph.apply_changeset(changeset);
+    }
+
+    /// Returns a [`ScriptChangeSet`] which recreates the current state of the tracker when applied
+    /// to a new tracker.
+    pub fn full_changeset(&self) -> ScriptChangeSet {
+        ChangeSet {
+            scripts: ScriptChanges {
+                scripts: self.scripts.clone(),
+                outpoints: self.outpoints.clone(),
+            },
+            ..self.graph.full_changeset()
+        }
+    }
+
+    pub fn clear_mempool(&mut self) -> ScriptChangeSet {
+        let mut changeset = ScriptChangeSet::default();
+        self.graph.clear_mempool(&mut changeset);
+        changeset
+    }
+
+    /// Removes the unconfirmed txs that were last seen before the unix timestamp `timestamp`. Use
+    /// this to expire txs when your updates don't have `mempool_is_total_set`.
+    pub fn prune_mempool_older_than(&mut self, timestamp: u64) -> ScriptChangeSet {
+        let mut changeset = ScriptChangeSet::default();
+        self.graph
+            .prune_mempool_older_than(timestamp, &mut changeset);
+        changeset
+    }
+
+    /// See [`DescriptorTracker::prune_evicted`](crate::DescriptorTracker::prune_evicted).
+    pub fn prune_evicted(&mut self, confirmations: u32) -> ScriptChangeSet {
+        let mut changeset = ScriptChangeSet::default();
+        self.graph.prune_evicted(confirmations, &mut changeset);
+        changeset
+    }
+
+    pub fn disconnect_block(
+        &mut self,
+        block_height: u32,
+        block_header: BlockHash,
+    ) -> ScriptChangeSet {
+        let mut changeset = ScriptChangeSet::default();
+        self.graph
+            .disconnect_block(block_height, block_header, &mut changeset);
+        changeset
+    }
+
+    pub fn iter_tx(&self) -> impl Iterator<Item = (Txid, &AugmentedTx)> {
+        self.graph.iter_tx()
+    }
+
+    pub fn get_tx(&self, txid: Txid) -> Option<&AugmentedTx> {
+        self.graph.get_tx(txid)
+    }
+
+    /// The balance of the watched txouts. Unconfirmed ones count as trusted if `trust_pending` is
+    /// true.
+    pub fn balance(&self, trust_pending: bool) -> Balance {
+        self.graph.balance(|_| trust_pending)
+    }
+
+    /// See [`DescriptorTracker::history`](crate::DescriptorTracker::history).
+    pub fn history(&self, offset: usize, limit: usize) -> Vec<HistoryEntry> {
+        self.graph.history(offset, limit)
+    }
+
+    pub fn iter_unspent(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
+        self.graph.iter_unspent().map(|(_, txout)| txout)
+    }
+
+    pub fn iter_txout(&self) -> impl Iterator<Item = LocalTxOut> + '_ {
+        self.graph.iter_txout().map(|(_, txout)| txout)
+    }
+
+    pub fn get_txout(&self, txo: OutPoint) -> Option<LocalTxOut> {
+        self.graph.get_txout(txo).map(|(_, txout)| txout)
+    }
+
+    /// The tracked txs that `txid` spends from, directly or through other tracked txs.
+    pub fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.ancestors(txid)
+    }
+
+    /// The tracked txs that spend from `txid`, directly or through other tracked txs.
+    pub fn descendants(&self, txid: Txid) -> HashSet<Txid> {
+        self.graph.descendants(txid)
+    }
+
+    /// The unconfirmed tx `txid` together with its unconfirmed ancestors along with their total
+    /// weight and fee. This is what a CPFP tx spending from `txid` has to pay for.
+    ///
+    /// Returns `None` if `txid` isn't tracked or is confirmed.
+    pub fn unconfirmed_ancestor_package(&self, txid: Txid) -> Option<AncestorPackage> {
+        self.graph.unconfirmed_ancestor_package(txid)
+    }
+
+    /// The txids of the unconfirmed txs with every tx after its parents. Txs seen first go first
+    /// where possible.
+    pub fn mempool_topological_order(&self) -> Vec<Txid> {
+        self.graph.mempool_topological_order()
+    }
+
+    pub fn replaced_by(&self, txid: Txid) -> Option<Txid> {
+        self.graph.replaced_by(txid)
+    }
+
+    pub fn iter_evicted(&self) -> impl Iterator<Item = (Txid, Txid)> + '_ {
+        self.graph.iter_evicted()
+    }
+
+    pub fn iter_spends(&self, outpoint: OutPoint) -> impl Iterator<Item = (u32, Txid)> + '_ {
+        self.graph.iter_spends(outpoint)
+    }
+}
+
+/// A [`ScriptTracker`] is serialized as the [`ScriptChangeSet`] that recreates it.
+#[cfg(feature = "serde")]
+impl serde::Serialize for ScriptTracker {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.full_changeset().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ScriptTracker {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut tracker = ScriptTracker::new();
+        tracker.apply_changeset(ScriptChangeSet::deserialize(deserializer)?);
+        Ok(tracker)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutils::*;
+    use alloc::vec::Vec;
+    use bitcoin::TxOut;
+
+    #[test]
+    fn tracks_scripts_and_outpoints() {
+        let mut tracker = ScriptTracker::new();
+        let deposit = Script::from(vec![0x51]);
+        let funding_script = Script::from(vec![0x52]);
+        let (deposit_index, mut changeset) = tracker.add_script(deposit.clone());
+
+        let funding_tx = spending_tx(
+            &[OutPoint::new(Txid::default(), 0)],
+            vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: funding_script.clone(),
+                },
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: deposit.clone(),
+                },
+            ],
+        );
+        let funding = OutPoint {
+            txid: funding_tx.txid(),
+            vout: 0,
+        };
+        let (funding_index, outpoint_changeset) = tracker.add_outpoint(funding);
+        assert_ne!(deposit_index, funding_index);
+        assert_eq!(tracker.add_script(deposit.clone()).0, deposit_index);
+        changeset.scripts.outpoints = outpoint_changeset.scripts.outpoints;
+        let mut changesets = vec![changeset];
+
+        // the funding script isn't known until the funding tx is found
+        assert_eq!(
+            tracker.iter_scripts().collect::<Vec<_>>(),
+            vec![(deposit_index, deposit.clone())]
+        );
+
+        match tracker.apply_update(update_from_txs(vec![(funding_tx.clone(), Some(1))], 1)) {
+            Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+            res => panic!("unexpected update result {:?}", res),
+        }
+        assert_eq!(tracker.iter_txout().count(), 2);
+        assert_eq!(
+            tracker.get_txout(funding).unwrap().derivation_index,
+            funding_index
+        );
+        assert!(tracker.is_used(funding_index));
+        assert_eq!(
+            tracker.iter_scripts().collect::<Vec<_>>(),
+            vec![(deposit_index, deposit), (funding_index, funding_script)]
+        );
+
+        let close = spending_tx(&[funding], vec![TxOut::default()]);
+        let mut update = update_from_txs(vec![(close.clone(), None)], 1);
+        update.base_tip = tracker.latest_checkpoint();
+        match tracker.apply_update(update) {
+            Ok(UpdateResult::Ok(changeset)) => changesets.push(changeset),
+            res => panic!("unexpected update result {:?}", res),
+        }
+        assert_eq!(
+            tracker.get_txout(funding).unwrap().spent_by,
+            Some((0, close.txid()))
+        );
+        assert_eq!(tracker.iter_unspent().count(), 1);
+
+        let mut replayed = ScriptTracker::new();
+        for changeset in changesets {
+            replayed.apply_changeset(changeset);
+        }
+        let mut snapshot = ScriptTracker::new();
+        snapshot.apply_changeset(tracker.full_changeset());
+        for restored in [replayed, snapshot] {
+            assert_eq!(
+                restored.iter_txout().collect::<Vec<_>>(),
+                tracker.iter_txout().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                restored.iter_scripts().collect::<Vec<_>>(),
+                tracker.iter_scripts().collect::<Vec<_>>()
+            );
+            assert_eq!(restored.latest_checkpoint(), tracker.latest_checkpoint());
+        }
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&tracker).unwrap();
+            let deserialized: ScriptTracker = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized.full_changeset(), tracker.full_changeset());
+            assert_eq!(
+                deserialized.iter_scripts().collect::<Vec<_>>(),
+                tracker.iter_scripts().collect::<Vec<_>>()
+            );
+        }
+    }
+}
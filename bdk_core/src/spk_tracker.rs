@@ -0,0 +1,1303 @@
+use crate::{
+    Amount, AncestorPackage, AugmentedTx, Balance, BlockTime, ChangeSet, CheckPoint, FeeRate,
+    HashMap, HashSet, HistoryEntry, LocalTxOut, PrevOuts, Update, UpdateError,
+};
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use bitcoin::{
+    hashes::{sha256, Hash},
+    BlockHash, OutPoint, Script, Transaction, Txid,
+};
+use core::{cmp::Reverse, ops::RangeInclusive};
+
+/// The index a [`SpkTracker`] keeps for each script pubkey it tracks.
+pub(crate) trait SpkIndex: Clone + Ord + core::fmt::Debug {
+    /// The derivation index of the script pubkey
+    fn derivation_index(&self) -> u32;
+}
+
+impl SpkIndex for u32 {
+    fn derivation_index(&self) -> u32 {
+        *self
+    }
+}
+
+impl<K: Clone + Ord + core::fmt::Debug> SpkIndex for (K, u32) {
+    fn derivation_index(&self) -> u32 {
+        self.1
+    }
+}
+
+/// Tracks the transactions related to a set of script pubkeys (and outpoints) along with the
+/// checkpoints they were confirmed in and the txouts that pay to the script pubkeys.
+///
+/// The trackers are built on top of this. They decide which script pubkeys to track and what the
+/// index of each one is.
+#[derive(Clone, Debug)]
+pub(crate) struct SpkTracker<I> {
+    /// Which txids are included in which checkpoints
+    checkpointed_txs: BTreeMap<u32, CheckpointData>,
+    /// The txouts owned by this tracker
+    txouts: BTreeMap<OutPoint, TxOutData<I>>,
+    /// The unspent txouts
+    unspent: HashSet<OutPoint>,
+    /// Index of outpoints to the inputs (input index, txid) of the txs that spend them.
+    ///
+    /// This includes outpoints that are not owned by the tracker so that we can find out that a
+    /// txout has been spent even if the spending tx arrives before the tx that created it.
+    spends: BTreeMap<OutPoint, HashSet<(u32, Txid)>>,
+    /// A lookup from script pubkey to its index
+    script_indexes: HashMap<Script, I>,
+    /// Outpoints that are tracked regardless of their script pubkey
+    outpoint_indexes: BTreeMap<OutPoint, I>,
+    /// A lookup from script pubkey index to outpoint
+    script_txouts: BTreeMap<I, HashSet<OutPoint>>,
+    /// Map from txid to metadata
+    txs: HashMap<Txid, AugmentedTx>,
+    /// Index of transactions that are in the mempool
+    mempool: HashSet<Txid>,
+    /// Txs that were evicted because they conflicted with another tx mapped to the txid of the
+    /// tx that replaced them.
+    evicted: HashMap<Txid, Txid>,
+    latest_blockheight: Option<u32>,
+}
+
+impl<I> Default for SpkTracker<I> {
+    fn default() -> Self {
+        Self {
+            checkpointed_txs: Default::default(),
+            txouts: Default::default(),
+            unspent: Default::default(),
+            spends: Default::default(),
+            script_indexes: Default::default(),
+            outpoint_indexes: Default::default(),
+            script_txouts: Default::default(),
+            txs: Default::default(),
+            mempool: Default::default(),
+            evicted: Default::default(),
+            latest_blockheight: Default::default(),
+        }
+    }
+}
+
+/// The outcome of checking an [`Update`] with [`SpkTracker::check_update`]
+pub(crate) enum UpdateCheck {
+    /// The update is not based on our latest checkpoint
+    Stale,
+    /// The update can be applied after invalidating the checkpoints from `invalidate_from`
+    Consistent { invalidate_from: Option<u32> },
+}
+
+impl<I: SpkIndex> SpkTracker<I> {
+    pub fn latest_blockheight(&self) -> Option<u32> {
+        self.latest_blockheight
+    }
+
+    pub fn latest_checkpoint(&self) -> Option<CheckPoint> {
+        self.checkpointed_txs
+            .iter()
+            .last()
+            .map(|(height, data)| CheckPoint {
+                height: *height,
+                hash: data.hash,
+            })
+    }
+
+    pub fn checkpoint_at(&self, height: u32) -> Option<CheckPoint> {
+        self.checkpointed_txs.get(&height).map(|data| CheckPoint {
+            height,
+            hash: data.hash,
+        })
+    }
+
+    pub fn checkpoint_commitment(&self, height: u32) -> Option<[u8; 32]> {
+        self.checkpointed_txs
+            .get(&height)
+            .map(|data| data.txid_commitment)
+    }
+
+    pub fn block_locator(&self) -> Vec<CheckPoint> {
+        let mut locator = Vec::new();
+        let mut step = 1u32;
+        let mut target = self.checkpointed_txs.keys().next_back().cloned();
+        while let Some(target_height) = target {
+            // the nearest checkpoint at or below the target height
+            let (height, data) = match self.checkpointed_txs.range(..=target_height).next_back() {
+                Some(checkpoint) => checkpoint,
+                None => break,
+            };
+            locator.push(CheckPoint {
+                height: *height,
+                hash: data.hash,
+            });
+            if locator.len() >= 10 {
+                step = step.saturating_mul(2);
+            }
+            target = height.checked_sub(step);
+        }
+
+        if let Some((height, data)) = self.checkpointed_txs.iter().next() {
+            if locator.last().map(|checkpoint| checkpoint.height) != Some(*height) {
+                locator.push(CheckPoint {
+                    height: *height,
+                    hash: data.hash,
+                });
+            }
+        }
+        locator
+    }
+
+    /// Keeps the latest `keep_recent` checkpoints (at least one). Below those it keeps the highest
+    /// checkpoint in each range of heights that is twice as far down as the one before. The txs of
+    /// a removed checkpoint are moved into the next checkpoint that is kept above it so that txs
+    /// only ever move to newer checkpoints (like when rebasing in `add_tx`).
+    pub fn prune_checkpoints<S>(&mut self, keep_recent: usize, changeset: &mut ChangeSet<S>) {
+        let oldest_recent = match self
+            .checkpointed_txs
+            .keys()
+            .rev()
+            .nth(keep_recent.max(1) - 1)
+        {
+            Some(height) => *height,
+            None => return,
+        };
+        // a checkpoint `d` blocks below the oldest recent one is in range `log2(d)`
+        let mut last_range = None;
+        let pruned = self
+            .checkpointed_txs
+            .range(..oldest_recent)
+            .rev()
+            .filter_map(|(height, _)| {
+                let range = 31 - (oldest_recent - height).leading_zeros();
+                if last_range == Some(range) {
+                    Some(*height)
+                } else {
+                    last_range = Some(range);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The commitment of the checkpoint the txs are moved into already covers them so none of
+        // the commitments change.
+        for height in pruned {
+            let removed = self
+                .checkpointed_txs
+                .remove(&height)
+                .expect("height was taken from the checkpoints");
+            let (kept_height, kept) = self
+                .checkpointed_txs
+                .range_mut(height..)
+                .next()
+                .expect("the latest checkpoint is always kept");
+            kept.txids.extend(removed.txids);
+            changeset.record_rebased(height);
+            changeset.new_checkpoints.insert(
+                *kept_height,
+                (kept.hash, kept.txids.iter().cloned().collect()),
+            );
+        }
+    }
+
+    pub fn iter_checkpoints(&self) -> impl Iterator<Item = (CheckPoint, &HashSet<Txid>)> {
+        self.checkpointed_txs.iter().map(|(height, data)| {
+            (
+                CheckPoint {
+                    height: *height,
+                    hash: data.hash,
+                },
+                &data.txids,
+            )
+        })
+    }
+
+    /// Starts tracking `script` under `index`.
+    pub fn add_script(&mut self, script: Script, index: I) {
+        self.script_indexes.insert(script, index);
+    }
+
+    pub fn index_of_script(&self, script: &Script) -> Option<&I> {
+        self.script_indexes.get(script)
+    }
+
+    /// Starts tracking the txout at `outpoint` under `index` whatever script pubkey it has.
+    pub fn add_outpoint(&mut self, outpoint: OutPoint, index: I) {
+        self.outpoint_indexes.insert(outpoint, index);
+    }
+
+    /// The indexes of the stored script pubkeys that the txs in `update` pay to.
+    pub fn indexes_paid_by<'a, A>(&'a self, update: &'a Update<A>) -> impl Iterator<Item = &'a I> {
+        update
+            .transactions
+            .iter()
+            .flat_map(|(_, tx, _, _)| tx.output.iter())
+            .filter_map(move |txout| self.index_of_script(&txout.script_pubkey))
+    }
+
+    /// Whether any txouts pay to the script pubkey at `index`
+    pub fn is_used(&self, index: &I) -> bool {
+        self.script_txouts
+            .get(index)
+            .map(|txos| !txos.is_empty())
+            .unwrap_or(false)
+    }
+
+    fn remove_tx(&mut self, txid: Txid) {
+        let txouts_to_remove = self
+            .txouts
+            .range(RangeInclusive::new(
+                OutPoint { txid, vout: 0 },
+                OutPoint {
+                    txid,
+                    vout: u32::MAX,
+                },
+            ))
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+
+        for txout_to_remove in txouts_to_remove {
+            if let Some(txout) = self.txouts.remove(&txout_to_remove) {
+                self.script_txouts
+                    .get_mut(&txout.index)
+                    .expect("guaranteed to exist")
+                    .remove(&txout_to_remove);
+                self.unspent.remove(&txout_to_remove);
+            }
+        }
+
+        if let Some(aug_tx) = self.txs.remove(&txid) {
+            for (i, input) in aug_tx.tx.input.iter().enumerate() {
+                let spend = (i as u32, txid);
+                if let Some(spends) = self.spends.get_mut(&input.previous_output) {
+                    spends.remove(&spend);
+                    if spends.is_empty() {
+                        self.spends.remove(&input.previous_output);
+                    }
+                }
+
+                if let Some(txout) = self.txouts.get_mut(&input.previous_output) {
+                    if txout.spent_by == Some(spend) {
+                        // fall back to any other tx we know of that spends it
+                        txout.spent_by = self
+                            .spends
+                            .get(&input.previous_output)
+                            .and_then(|spends| spends.iter().next().cloned());
+                        if txout.spent_by.is_none() {
+                            // this previous spent output is now unspent
+                            self.unspent.insert(input.previous_output);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.mempool.remove(&txid);
+    }
+
+    /// The txs in the tracker that spend any of the same outpoints as `tx`.
+    fn conflicts_of(&self, tx: &Transaction) -> HashSet<Txid> {
+        let txid = tx.txid();
+        tx.input
+            .iter()
+            .flat_map(|input| self.iter_spends(input.previous_output))
+            .map(|(_, spending_txid)| spending_txid)
+            .filter(|spending_txid| *spending_txid != txid)
+            .collect()
+    }
+
+    /// Removes a tx and all of its descendants from the tracker and records that they were
+    /// replaced by `replaced_by`.
+    fn evict_tx<S>(&mut self, txid: Txid, replaced_by: Txid, changeset: &mut ChangeSet<S>) {
+        let mut to_evict = vec![txid];
+        while let Some(txid) = to_evict.pop() {
+            to_evict.extend(self.children_of(txid));
+            self.remove_tx(txid);
+            self.evicted.insert(txid, replaced_by);
+            changeset.record_removed(txid);
+            changeset.pruned_evicted_txs.remove(&txid);
+            changeset.evicted_txs.insert(txid, replaced_by);
+        }
+    }
+
+    fn add_tx<S>(
+        &mut self,
+        inputs: PrevOuts,
+        tx: Transaction,
+        confirmation_time: Option<BlockTime>,
+        seen_at: Option<u64>,
+        changeset: &mut ChangeSet<S>,
+    ) {
+        let txid = tx.txid();
+        // we only keep track of when txs were seen in the mempool
+        let seen_at = seen_at.filter(|_| confirmation_time.is_none());
+        let (mut first_seen, mut last_seen) = (seen_at, seen_at);
+        // compare to potentially existing tx in the database
+        if let Some(existing) = self.txs.get_mut(&txid) {
+            match (existing.confirmation_time, confirmation_time) {
+                (Some(existing_time), Some(new_time)) => {
+                    debug_assert_eq!(
+                        existing_time, new_time,
+                        "the caller must have checked the confirmation time can't change"
+                    );
+                    return;
+                }
+                (Some(_), None) => {
+                    unreachable!("the caller must have checked that confirmed txs stay confirmed")
+                }
+                (None, None) => {
+                    if let Some(seen_at) = seen_at {
+                        if record_seen(existing, seen_at) {
+                            changeset.record_added(existing.clone());
+                        }
+                    }
+                    return;
+                }
+                (None, Some(_)) => {
+                    // it's been confirmed so take it out of the mempool and add it again below
+                    // (remembering when we saw it there).
+                    first_seen = existing.first_seen;
+                    last_seen = existing.last_seen;
+                    self.remove_tx(txid);
+                    changeset.record_removed(txid);
+                }
+            }
+        }
+
+        if confirmation_time.is_none() {
+            // a tx spending the output of a tx that was replaced has been replaced too
+            let replaced_by = tx
+                .input
+                .iter()
+                .find_map(|input| self.evicted.get(&input.previous_output.txid))
+                .cloned();
+            if let Some(replaced_by) = replaced_by {
+                self.evict_tx(txid, replaced_by, changeset);
+                return;
+            }
+        }
+
+        // A confirmed tx always beats an unconfirmed one. Between two unconfirmed txs the one we've
+        // seen most recently wins where a tx with a seen time counts as more recent than one
+        // without. Ties go to the larger txid so the winner doesn't depend on the order the txs
+        // are added in.
+        let conflicts = self.conflicts_of(&tx);
+        for conflict in conflicts {
+            let (conflict_is_confirmed, conflict_wins) = match self.txs.get(&conflict) {
+                Some(conflict_tx) => (
+                    conflict_tx.confirmation_time.is_some(),
+                    (conflict_tx.last_seen, conflict) > (seen_at, txid),
+                ),
+                // it was a descendant of a conflict we've already evicted
+                None => continue,
+            };
+            match (conflict_is_confirmed, confirmation_time.is_some()) {
+                (true, false) => {
+                    self.evict_tx(txid, conflict, changeset);
+                    return;
+                }
+                (false, false) if conflict_wins => {
+                    self.evict_tx(txid, conflict, changeset);
+                    return;
+                }
+                (false, _) => self.evict_tx(conflict, txid, changeset),
+                // two confirmed txs can't conflict unless the checkpoints are inconsistent
+                (true, true) => {}
+            }
+        }
+        let mut inputs_sum: u64 = 0;
+        let outputs_sum: u64 = tx.output.iter().map(|out| out.value).sum();
+        let is_coinbase = matches!(inputs, PrevOuts::Coinbase);
+
+        match inputs {
+            PrevOuts::Coinbase => {
+                debug_assert_eq!(tx.input.len(), 1);
+                debug_assert!(tx.input[0].previous_output.is_null());
+            }
+            PrevOuts::Spend(txouts) => {
+                for txout in txouts.iter() {
+                    inputs_sum += txout.value;
+                }
+            }
+        }
+
+        if let Some(confirmation_time) = confirmation_time {
+            // Find the first checkpoint above or equal to the tx's height
+            let checkpoint_height: Option<u32> = self
+                .checkpointed_txs
+                .range(confirmation_time.height..)
+                .next()
+                .map(|(height, _)| *height);
+
+            match checkpoint_height {
+                Some(checkpoint_height) => {
+                    // Rebase onto the checkpoint, removing all checkpoints after after
+                    // and including the target. We do this to keep the rule: Never add new txs
+                    // to a checkpoint once you've added it into checkpointed_txs. But we *can*
+                    // remove checkpoints and move the txids from older ones to the tip.
+                    //
+                    // NOTE: the usual case is that checkpoint_height == tip_height in which
+                    // case the following will just insert the new txid into the tip.
+                    //
+                    // The tip's commitment already covers the txs of the checkpoints we merge
+                    // into it so we only need to add the new txid.
+                    let removed = self.checkpointed_txs.split_off(&checkpoint_height);
+                    let (tip_height, tip) = removed.iter().next_back().unwrap();
+                    let txids: HashSet<Txid> = removed
+                        .values()
+                        .flat_map(|data| data.txids.iter().cloned())
+                        .chain(core::iter::once(txid))
+                        .collect();
+                    let mut txid_commitment = tip.txid_commitment;
+                    xor_txid(&mut txid_commitment, txid);
+
+                    for rebased_height in removed.keys().filter(|height| *height != tip_height) {
+                        changeset.record_rebased(*rebased_height);
+                    }
+                    changeset
+                        .new_checkpoints
+                        .insert(*tip_height, (tip.hash, txids.iter().cloned().collect()));
+
+                    self.checkpointed_txs.insert(
+                        *tip_height,
+                        CheckpointData {
+                            hash: tip.hash,
+                            txids,
+                            txid_commitment,
+                        },
+                    );
+                }
+                None => {
+                    unreachable!("the caller must have checked that no txs are outside of range")
+                }
+            }
+        }
+
+        // we need to saturating sub since we want coinbase txs to map to 0 fee and
+        // this subtraction will be negative for coinbase txs.
+        let fee = inputs_sum.saturating_sub(outputs_sum);
+        let feerate = FeeRate::from_fee_and_weight(fee, tx.weight() as u32);
+
+        let aug_tx = AugmentedTx {
+            tx,
+            fee,
+            feerate,
+            confirmation_time,
+            is_coinbase,
+            first_seen,
+            last_seen,
+        };
+        self.insert_tx(aug_tx.clone());
+        changeset.record_added(aug_tx);
+    }
+
+    /// Indexes a tx and its txouts. The caller must have already put it into a checkpoint if it's
+    /// confirmed.
+    fn insert_tx(&mut self, aug_tx: AugmentedTx) {
+        let tx = &aug_tx.tx;
+        let txid = tx.txid();
+
+        if !aug_tx.is_coinbase {
+            for (i, input) in tx.input.iter().enumerate() {
+                let spend = (i as u32, txid);
+                self.spends
+                    .entry(input.previous_output)
+                    .or_default()
+                    .insert(spend);
+
+                if let Some(txout) = self.txouts.get_mut(&input.previous_output) {
+                    if txout.spent_by.is_none() {
+                        txout.spent_by = Some(spend);
+                    }
+                    self.unspent.remove(&input.previous_output);
+                }
+            }
+        }
+
+        for (i, out) in tx.output.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                vout: i as u32,
+            };
+            let index = self
+                .index_of_script(&out.script_pubkey)
+                .or_else(|| self.outpoint_indexes.get(&outpoint))
+                .cloned();
+            if let Some(index) = index {
+                // It may be an old tx that we've just found out about that has already been spent
+                // by a tx in our state.
+                let spent_by = self
+                    .spends
+                    .get(&outpoint)
+                    .and_then(|spends| spends.iter().next().cloned());
+
+                self.txouts.insert(
+                    outpoint,
+                    TxOutData {
+                        value: out.value,
+                        spent_by,
+                        index: index.clone(),
+                    },
+                );
+
+                if spent_by.is_none() {
+                    self.unspent.insert(outpoint);
+                }
+
+                let txos_for_script = self.script_txouts.entry(index).or_default();
+                txos_for_script.insert(outpoint);
+            }
+        }
+
+        if aug_tx.confirmation_time.is_none() {
+            self.mempool.insert(txid);
+        }
+
+        self.evicted.remove(&txid);
+        self.txs.insert(txid, aug_tx);
+    }
+
+    fn invalidate_checkpoint<S>(&mut self, height: u32, changeset: &mut ChangeSet<S>) {
+        let removed = self.checkpointed_txs.split_off(&height);
+        changeset.record_invalidated(removed.iter().map(|(height, data)| CheckPoint {
+            height: *height,
+            hash: data.hash,
+        }));
+        let txs_to_remove = removed.values().flat_map(|data| &data.txids);
+        for tx_to_remove in txs_to_remove {
+            self.remove_tx(*tx_to_remove);
+        }
+    }
+
+    /// Checks whether `update` can be applied without changing anything.
+    pub fn check_update<A>(&self, update: &Update<A>) -> Result<UpdateCheck, UpdateError> {
+        let invalidate_from = match update.invalidate.iter().map(|cp| cp.height).min() {
+            Some(lowest_invalidated) => {
+                // The checkpoints the update invalidates must be ones we have. If we have a
+                // different block at one of their heights we've already moved past this reorg.
+                for checkpoint in &update.invalidate {
+                    match self.checkpointed_txs.get(&checkpoint.height) {
+                        Some(existing) if existing.hash == checkpoint.hash => {}
+                        Some(_) => return Ok(UpdateCheck::Stale),
+                        None => {
+                            return Err(UpdateError::MissingCheckpoint {
+                                height: checkpoint.height,
+                            })
+                        }
+                    }
+                }
+                // Everything above the base tip is invalidated, not just the given checkpoints,
+                // since the update doesn't know about the checkpoints in between.
+                match update.base_tip {
+                    Some(base_tip) => {
+                        if base_tip.height >= lowest_invalidated
+                            || self.checkpoint_at(base_tip.height) != Some(base_tip)
+                        {
+                            return Ok(UpdateCheck::Stale);
+                        }
+                        Some(base_tip.height + 1)
+                    }
+                    None => Some(0),
+                }
+            }
+            None => {
+                if update.base_tip != self.latest_checkpoint() {
+                    return Ok(UpdateCheck::Stale);
+                }
+                None
+            }
+        };
+
+        self.check_update_txs(update, invalidate_from)?;
+
+        Ok(UpdateCheck::Consistent { invalidate_from })
+    }
+
+    /// Applies an update that has passed [`check_update`](Self::check_update). The scripts the
+    /// update found txs for must have been added before calling this.
+    pub fn apply_checked_update<A, S>(
+        &mut self,
+        update: Update<A>,
+        invalidate_from: Option<u32>,
+        changeset: &mut ChangeSet<S>,
+    ) {
+        if let Some(invalidate_from) = invalidate_from {
+            self.invalidate_checkpoint(invalidate_from, changeset);
+        }
+
+        // If the update includes everything in the mempool that is relevent to the tracker then
+        // anything in our mempool that isn't in the update has left the mempool. We remove these
+        // only after adding the update's txs so that we can find out which were replaced.
+        let mut stale_mempool = if update.mempool_is_total_set {
+            self.mempool.clone()
+        } else {
+            Default::default()
+        };
+
+        // Insert a new empty checkpoint at the update height
+        let txid_commitment = self
+            .checkpointed_txs
+            .range(..update.new_tip.height)
+            .last()
+            .map(|(_, data)| data.txid_commitment)
+            .unwrap_or_default();
+        if let Entry::Vacant(entry) = self.checkpointed_txs.entry(update.new_tip.height) {
+            entry.insert(CheckpointData {
+                hash: update.new_tip.hash,
+                txids: Default::default(),
+                txid_commitment,
+            });
+            changeset.new_checkpoints.insert(
+                update.new_tip.height,
+                (update.new_tip.hash, Default::default()),
+            );
+        }
+
+        // NOTE: The trackers store the scripts the update's txs pay to (within their lookahead)
+        // before calling this. A tx paying to a script we haven't stored is missed.
+        for (vouts, tx, confirmation_time, seen_at) in update.transactions {
+            stale_mempool.remove(&tx.txid());
+            self.add_tx(vouts, tx, confirmation_time, seen_at, changeset);
+        }
+
+        for txid in stale_mempool {
+            if self.mempool.contains(&txid) {
+                self.remove_tx(txid);
+                changeset.record_removed(txid);
+            }
+        }
+
+        let tip = self.checkpointed_txs.values().next_back().unwrap();
+
+        if tip.txids.is_empty() {
+            // the new checkpoint we inserted ends up empty so delete it
+            self.checkpointed_txs.remove(&update.new_tip.height);
+            changeset.new_checkpoints.remove(&update.new_tip.height);
+        }
+
+        self.latest_blockheight = Some(update.new_tip.height);
+        changeset.latest_blockheight = Some(update.new_tip.height);
+    }
+
+    /// Applies the changes to the txs and checkpoints in `changeset`. The scripts the changeset
+    /// found txs for must have been added before calling this.
+    pub fn apply_changeset<S>(&mut self, changeset: ChangeSet<S>) {
+        if let Some(invalidate_from) = changeset
+            .invalidated_checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.height)
+            .min()
+        {
+            self.invalidate_checkpoint(invalidate_from, &mut ChangeSet::<()>::default());
+        }
+
+        for txid in changeset.removed_txs {
+            self.remove_tx(txid);
+        }
+
+        let lowest_changed_checkpoint = changeset
+            .rebased_checkpoints
+            .iter()
+            .chain(changeset.new_checkpoints.keys())
+            .min()
+            .cloned();
+
+        for height in changeset.rebased_checkpoints {
+            self.checkpointed_txs.remove(&height);
+        }
+
+        for (height, (hash, txids)) in changeset.new_checkpoints {
+            self.checkpointed_txs.insert(
+                height,
+                CheckpointData {
+                    hash,
+                    txids: txids.into_iter().collect(),
+                    txid_commitment: Default::default(),
+                },
+            );
+        }
+
+        if let Some(lowest_changed_checkpoint) = lowest_changed_checkpoint {
+            self.recompute_txid_commitments(lowest_changed_checkpoint);
+        }
+
+        for (_, aug_tx) in changeset.added_txs {
+            self.insert_tx(aug_tx);
+        }
+
+        self.evicted.extend(changeset.evicted_txs);
+        for txid in changeset.pruned_evicted_txs {
+            self.evicted.remove(&txid);
+        }
+
+        if let Some(latest_blockheight) = changeset.latest_blockheight {
+            self.latest_blockheight = Some(latest_blockheight);
+        }
+    }
+
+    /// Returns a [`ChangeSet`] which recreates the txs and checkpoints of the tracker. The caller
+    /// fills in `scripts`.
+    pub fn full_changeset<S: Default>(&self) -> ChangeSet<S> {
+        ChangeSet {
+            new_checkpoints: self
+                .checkpointed_txs
+                .iter()
+                .map(|(height, data)| (*height, (data.hash, data.txids.iter().cloned().collect())))
+                .collect(),
+            added_txs: self
+                .txs
+                .iter()
+                .map(|(txid, aug_tx)| (*txid, aug_tx.clone()))
+                .collect(),
+            evicted_txs: self
+                .evicted
+                .iter()
+                .map(|(txid, replaced_by)| (*txid, *replaced_by))
+                .collect(),
+            latest_blockheight: self.latest_blockheight,
+            ..Default::default()
+        }
+    }
+
+    fn recompute_txid_commitments(&mut self, from: u32) {
+        let mut txid_commitment = self
+            .checkpointed_txs
+            .range(..from)
+            .last()
+            .map(|(_, data)| data.txid_commitment)
+            .unwrap_or_default();
+
+        for (_, data) in self.checkpointed_txs.range_mut(from..) {
+            for txid in &data.txids {
+                xor_txid(&mut txid_commitment, *txid);
+            }
+            data.txid_commitment = txid_commitment;
+        }
+    }
+
+    /// Checks that the txs in the update are consistent with the new tip, with each other and with
+    /// the txs we already have (apart from those in checkpoints at or above `invalidate_from`).
+    fn check_update_txs<A>(
+        &self,
+        update: &Update<A>,
+        invalidate_from: Option<u32>,
+    ) -> Result<(), UpdateError> {
+        let invalidated_txs = match invalidate_from {
+            Some(invalidate_from) => self
+                .checkpointed_txs
+                .range(invalidate_from..)
+                .flat_map(|(_, data)| data.txids.iter().cloned())
+                .collect(),
+            None => HashSet::new(),
+        };
+        let mut update_times = HashMap::new();
+
+        for (_, tx, confirmation_time, _) in &update.transactions {
+            let txid = tx.txid();
+            if let Some(confirmation_time) = confirmation_time {
+                if confirmation_time.height > update.new_tip.height {
+                    return Err(UpdateError::TxConfirmedAboveTip {
+                        txid,
+                        confirmation_height: confirmation_time.height,
+                        tip_height: update.new_tip.height,
+                    });
+                }
+            }
+
+            // the update may list a tx more than once but it has to agree with itself
+            if let Some(earlier_time) = update_times.insert(txid, *confirmation_time) {
+                if earlier_time != *confirmation_time {
+                    let (confirmed, other) = match earlier_time {
+                        Some(earlier_time) => (earlier_time, *confirmation_time),
+                        None => (confirmation_time.expect("they differ"), None),
+                    };
+                    return Err(UpdateError::InconsistentConfirmation {
+                        txid,
+                        existing_height: confirmed.height,
+                        update_height: other.map(|time| time.height),
+                    });
+                }
+            }
+
+            if invalidated_txs.contains(&txid) {
+                continue;
+            }
+
+            if let Some(existing_time) = self
+                .txs
+                .get(&txid)
+                .and_then(|existing| existing.confirmation_time)
+            {
+                if Some(existing_time) != *confirmation_time {
+                    return Err(UpdateError::InconsistentConfirmation {
+                        txid,
+                        existing_height: existing_time.height,
+                        update_height: confirmation_time.map(|time| time.height),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn clear_mempool<S>(&mut self, changeset: &mut ChangeSet<S>) {
+        let mempool = core::mem::take(&mut self.mempool);
+        for txid in mempool {
+            self.remove_tx(txid);
+            changeset.record_removed(txid);
+        }
+
+        debug_assert!(self.mempool.is_empty());
+    }
+
+    /// Removes the unconfirmed txs that were last seen before `timestamp`. Txs that we don't have
+    /// a last seen time for are kept.
+    ///
+    /// This is how stale txs get dropped when updates don't include the full mempool.
+    pub fn prune_mempool_older_than<S>(&mut self, timestamp: u64, changeset: &mut ChangeSet<S>) {
+        let stale = self
+            .mempool
+            .iter()
+            .filter(|txid| {
+                self.txs[*txid]
+                    .last_seen
+                    .map(|last_seen| last_seen < timestamp)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for txid in stale {
+            self.remove_tx(txid);
+            changeset.record_removed(txid);
+        }
+    }
+
+    /// Forgets the evicted txs whose replacement has at least `confirmations` confirmations. If
+    /// the replacement was itself replaced the tx that replaced it is checked instead.
+    pub fn prune_evicted<S>(&mut self, confirmations: u32, changeset: &mut ChangeSet<S>) {
+        let latest_blockheight = match self.latest_blockheight {
+            Some(latest_blockheight) => latest_blockheight,
+            None => return,
+        };
+        let pruned = self
+            .evicted
+            .keys()
+            .filter(|txid| {
+                self.txs
+                    .get(&self.final_replacement(**txid))
+                    .and_then(|aug_tx| aug_tx.confirmation_time)
+                    .map(|confirmed_at| {
+                        (latest_blockheight + 1).saturating_sub(confirmed_at.height)
+                            >= confirmations
+                    })
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for txid in pruned {
+            self.evicted.remove(&txid);
+            changeset.evicted_txs.remove(&txid);
+            changeset.pruned_evicted_txs.insert(txid);
+        }
+    }
+
+    /// Follows the txs that replaced `txid` until one that hasn't been evicted.
+    fn final_replacement(&self, mut txid: Txid) -> Txid {
+        // a tx that comes back is no longer evicted so this can't loop but bound it anyway
+        for _ in 0..=self.evicted.len() {
+            match self.evicted.get(&txid) {
+                Some(replaced_by) => txid = *replaced_by,
+                None => break,
+            }
+        }
+        txid
+    }
+
+    pub fn disconnect_block<S>(
+        &mut self,
+        block_height: u32,
+        block_header: BlockHash,
+        changeset: &mut ChangeSet<S>,
+    ) {
+        // Can't guarantee that mempool is consistent with chain after we disconnect a block so we
+        // clear it.
+        // TODO: it would be nice if we could only delete those transactions that are
+        // inconsistent by recording the latest block they were included in.
+        self.clear_mempool(changeset);
+        if let Some(existing) = self.checkpointed_txs.get(&block_height) {
+            if existing.hash == block_header {
+                self.invalidate_checkpoint(block_height, changeset);
+            }
+        }
+    }
+
+    pub fn iter_tx(&self) -> impl Iterator<Item = (Txid, &AugmentedTx)> {
+        self.txs.iter().map(|(txid, tx)| (*txid, tx))
+    }
+
+    /// A page of at most `limit` entries of the history of every tx we know about along with how
+    /// it affected our txouts starting at `offset`, most recent first.
+    ///
+    /// Unconfirmed txs come first followed by confirmed ones in descending height. Txs in the same
+    /// block (or both unconfirmed) are ordered by when we first saw them, latest first, but always
+    /// with children before their parents. Txid breaks any remaining ties.
+    ///
+    /// Since checkpoints hold the txs of a range of heights only the checkpoints (and the mempool)
+    /// that overlap the page are looked at and sorted. The ones before the page are only counted.
+    pub fn history(&self, mut offset: usize, limit: usize) -> Vec<HistoryEntry> {
+        let groups = core::iter::once(&self.mempool)
+            .chain(self.checkpointed_txs.values().rev().map(|data| &data.txids));
+        let mut page = Vec::new();
+        for txids in groups {
+            if page.len() >= limit {
+                break;
+            }
+            if offset >= txids.len() {
+                offset -= txids.len();
+                continue;
+            }
+            page.extend(
+                self.sorted_history(txids)
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit - page.len()),
+            );
+            offset = 0;
+        }
+        page
+    }
+
+    /// The history entries of `txids` which must be the txs of a single checkpoint or the mempool
+    /// in the order described in [`history`](Self::history).
+    fn sorted_history(&self, txids: &HashSet<Txid>) -> Vec<HistoryEntry> {
+        let mut positions = BTreeMap::new();
+        let mut history = txids
+            .iter()
+            .map(|txid| {
+                let aug_tx = &self.txs[txid];
+                let sent = aug_tx
+                    .tx
+                    .input
+                    .iter()
+                    .filter_map(|input| self.txouts.get(&input.previous_output))
+                    .map(|data| Amount::from_sat(data.value))
+                    .sum();
+                let received = self
+                    .txouts
+                    .range(OutPoint::new(*txid, 0)..=OutPoint::new(*txid, u32::MAX))
+                    .map(|(_, data)| Amount::from_sat(data.value))
+                    .sum();
+                let position = self.position_within_block(*txid, &mut positions);
+                let entry = HistoryEntry {
+                    txid: *txid,
+                    sent,
+                    received,
+                    fee: Amount::from_sat(aug_tx.fee),
+                    feerate: aug_tx.feerate,
+                    confirmation_time: aug_tx.confirmation_time,
+                };
+                (entry, position)
+            })
+            .collect::<Vec<_>>();
+
+        history.sort_by_key(|(entry, position)| {
+            let height = entry
+                .confirmation_time
+                .map(|time| time.height)
+                .unwrap_or(u32::MAX);
+            (Reverse(height), Reverse(*position), entry.txid)
+        });
+
+        history.into_iter().map(|(entry, _)| entry).collect()
+    }
+
+    /// Where `txid` goes among the txs confirmed in the same block (or the unconfirmed txs if it
+    /// is unconfirmed), earliest first.
+    ///
+    /// This is the latest first seen time of it and its ancestors in the block along with the length
+    /// of the longest chain of those ancestors. A tx can't go before any of its ancestors.
+    fn position_within_block(
+        &self,
+        txid: Txid,
+        positions: &mut BTreeMap<Txid, (Option<u64>, u32)>,
+    ) -> (Option<u64>, u32) {
+        if let Some(position) = positions.get(&txid) {
+            return *position;
+        }
+        let aug_tx = self.txs.get(&txid).expect("tx must exist");
+        let height = aug_tx.confirmation_time.map(|time| time.height);
+        let position = aug_tx
+            .tx
+            .input
+            .iter()
+            .map(|input| input.previous_output.txid)
+            .filter(|parent| {
+                self.txs
+                    .get(parent)
+                    .map(|parent| parent.confirmation_time.map(|time| time.height) == height)
+                    .unwrap_or(false)
+            })
+            .map(|parent| self.position_within_block(parent, positions))
+            .fold(
+                (aug_tx.first_seen, 0),
+                |(seen, depth), (parent_seen, parent_depth)| {
+                    (seen.max(parent_seen), depth.max(parent_depth + 1))
+                },
+            );
+        positions.insert(txid, position);
+        position
+    }
+
+    pub fn iter_unspent(&self) -> impl Iterator<Item = (&I, LocalTxOut)> + '_ {
+        self.unspent
+            .iter()
+            .map(|txo| (txo, self.txouts.get(txo).expect("txout must exist")))
+            .map(|(txo, data)| (&data.index, self.create_txout(*txo, data)))
+    }
+
+    /// The balance of the unspent txouts. Unconfirmed txouts are trusted if `should_trust` returns
+    /// true for their index.
+    pub fn balance(&self, mut should_trust: impl FnMut(&I) -> bool) -> Balance {
+        let next_height = self.next_block_height();
+        let mut balance = Balance::default();
+        for (index, txout) in self.iter_unspent() {
+            let value = Amount::from_sat(txout.value);
+            if !txout.is_spendable_at(next_height) {
+                balance.immature += value;
+            } else if txout.confirmed_at.is_some() {
+                balance.confirmed += value;
+            } else if should_trust(index) {
+                balance.trusted_pending += value;
+            } else {
+                balance.untrusted_pending += value;
+            }
+        }
+        balance
+    }
+
+    /// The height of the block after our latest one. This is the earliest a tx we create now can
+    /// be confirmed.
+    pub fn next_block_height(&self) -> u32 {
+        self.latest_blockheight
+            .map(|height| height + 1)
+            .unwrap_or(0)
+    }
+
+    /// Iterates over the unspent txouts that a tx confirmed at `height` could spend.
+    pub fn iter_spendable_unspent(
+        &self,
+        height: u32,
+    ) -> impl Iterator<Item = (&I, LocalTxOut)> + '_ {
+        self.iter_unspent()
+            .filter(move |(_, txout)| txout.is_spendable_at(height))
+    }
+
+    fn create_txout(&self, outpoint: OutPoint, data: &TxOutData<I>) -> LocalTxOut {
+        let tx = self
+            .txs
+            .get(&outpoint.txid)
+            .expect("must exist since we have the txout");
+        LocalTxOut {
+            value: data.value,
+            spent_by: data.spent_by,
+            outpoint,
+            derivation_index: data.index.derivation_index(),
+            confirmed_at: tx.confirmation_time,
+            is_coinbase: tx.is_coinbase,
+        }
+    }
+
+    pub fn iter_txout(&self) -> impl Iterator<Item = (&I, LocalTxOut)> + '_ {
+        self.txouts
+            .iter()
+            .map(|(outpoint, data)| (&data.index, self.create_txout(*outpoint, data)))
+    }
+
+    pub fn get_txout(&self, txo: OutPoint) -> Option<(&I, LocalTxOut)> {
+        let data = self.txouts.get(&txo)?;
+        Some((&data.index, self.create_txout(txo, data)))
+    }
+
+    pub fn get_tx(&self, txid: Txid) -> Option<&AugmentedTx> {
+        self.txs.get(&txid)
+    }
+
+    pub fn replaced_by(&self, txid: Txid) -> Option<Txid> {
+        self.evicted.get(&txid).cloned()
+    }
+
+    pub fn iter_evicted(&self) -> impl Iterator<Item = (Txid, Txid)> + '_ {
+        self.evicted
+            .iter()
+            .map(|(txid, replaced_by)| (*txid, *replaced_by))
+    }
+
+    /// The txids of the txs that spend an output of `txid`. A tx appears once for each output it
+    /// spends.
+    fn children_of(&self, txid: Txid) -> impl Iterator<Item = Txid> + '_ {
+        self.spends
+            .range(RangeInclusive::new(
+                OutPoint { txid, vout: 0 },
+                OutPoint {
+                    txid,
+                    vout: u32::MAX,
+                },
+            ))
+            .flat_map(|(_, spends)| spends.iter().map(|(_, spending_txid)| *spending_txid))
+    }
+
+    /// The txids of the tracked txs that `txid` spends an output of. A tx appears once for each
+    /// output of it that is spent.
+    fn parents_of(&self, txid: Txid) -> impl Iterator<Item = Txid> + '_ {
+        self.txs
+            .get(&txid)
+            .into_iter()
+            .flat_map(|aug_tx| aug_tx.tx.input.iter())
+            .map(|input| input.previous_output.txid)
+            .filter(move |parent| self.txs.contains_key(parent))
+    }
+
+    /// All the tracked txs that `txid` depends on i.e. its parents, their parents and so on.
+    pub fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        let mut ancestors = HashSet::new();
+        let mut to_visit = self.parents_of(txid).collect::<Vec<_>>();
+        while let Some(ancestor) = to_visit.pop() {
+            if ancestors.insert(ancestor) {
+                to_visit.extend(self.parents_of(ancestor));
+            }
+        }
+        ancestors
+    }
+
+    /// All the tracked txs that depend on `txid` i.e. its children, their children and so on.
+    ///
+    /// Like [`ancestors`](Self::ancestors) this is empty if `txid` isn't tracked itself.
+    pub fn descendants(&self, txid: Txid) -> HashSet<Txid> {
+        let mut descendants = HashSet::new();
+        if !self.txs.contains_key(&txid) {
+            return descendants;
+        }
+        let mut to_visit = self.children_of(txid).collect::<Vec<_>>();
+        while let Some(descendant) = to_visit.pop() {
+            if descendants.insert(descendant) {
+                to_visit.extend(self.children_of(descendant));
+            }
+        }
+        descendants
+    }
+
+    /// The unconfirmed tx `txid` along with all of its unconfirmed ancestors. These all have to be
+    /// confirmed together so a tx bumping the fee of `txid` has to pay for the whole package.
+    ///
+    /// Returns `None` if the tx isn't tracked or is already confirmed.
+    pub fn unconfirmed_ancestor_package(&self, txid: Txid) -> Option<AncestorPackage> {
+        let aug_tx = self.txs.get(&txid)?;
+        if aug_tx.confirmation_time.is_some() {
+            return None;
+        }
+
+        let mut package = AncestorPackage::default();
+        let mut to_visit = vec![txid];
+        while let Some(txid) = to_visit.pop() {
+            let aug_tx = &self.txs[&txid];
+            // the parents of a confirmed tx are confirmed too
+            if aug_tx.confirmation_time.is_some() || !package.txids.insert(txid) {
+                continue;
+            }
+            package.weight += aug_tx.tx.weight() as u32;
+            package.fee += aug_tx.fee;
+            to_visit.extend(self.parents_of(txid));
+        }
+        Some(package)
+    }
+
+    /// The unconfirmed txs ordered so that every tx comes after its parents. Txs we saw first in
+    /// the mempool go first where possible and the ones we never saw there go last.
+    pub fn mempool_topological_order(&self) -> Vec<Txid> {
+        let ready_key = |txid: Txid| {
+            let first_seen = self.txs[&txid].first_seen;
+            (first_seen.is_none(), first_seen, txid)
+        };
+        let mut unplaced_parents = self
+            .mempool
+            .iter()
+            .map(|txid| {
+                let n_parents = self
+                    .parents_of(*txid)
+                    .filter(|parent| self.mempool.contains(parent))
+                    .count();
+                (*txid, n_parents)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut ready = unplaced_parents
+            .iter()
+            .filter(|(_, n_parents)| **n_parents == 0)
+            .map(|(txid, _)| ready_key(*txid))
+            .collect::<BTreeSet<_>>();
+
+        let mut order = Vec::with_capacity(unplaced_parents.len());
+        while let Some(next) = ready.iter().next().cloned() {
+            ready.remove(&next);
+            let (_, _, txid) = next;
+            order.push(txid);
+            for child in self.children_of(txid) {
+                if let Some(n_parents) = unplaced_parents.get_mut(&child) {
+                    *n_parents -= 1;
+                    if *n_parents == 0 {
+                        ready.insert(ready_key(child));
+                    }
+                }
+            }
+        }
+        order
+    }
+
+    pub fn iter_spends(&self, outpoint: OutPoint) -> impl Iterator<Item = (u32, Txid)> + '_ {
+        self.spends
+            .get(&outpoint)
+            .into_iter()
+            .flat_map(|spends| spends.iter().cloned())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CheckpointData {
+    hash: BlockHash,
+    /// The txids that were added to the tracker at this checkpoint
+    txids: HashSet<Txid>,
+    /// The XOR of `sha256(txid)` of every txid in this checkpoint and the ones below it
+    txid_commitment: [u8; 32],
+}
+
+fn xor_txid(commitment: &mut [u8; 32], txid: Txid) {
+    let txid_hash = sha256::Hash::hash(&txid[..]);
+    for (byte, txid_byte) in commitment.iter_mut().zip(txid_hash.into_inner()) {
+        *byte ^= txid_byte;
+    }
+}
+
+/// Widens the first and last seen times of `aug_tx` to include `seen_at`. Returns whether either
+/// of them changed.
+fn record_seen(aug_tx: &mut AugmentedTx, seen_at: u64) -> bool {
+    let first_seen = Some(
+        aug_tx
+            .first_seen
+            .map_or(seen_at, |first| first.min(seen_at)),
+    );
+    let last_seen = Some(aug_tx.last_seen.map_or(seen_at, |last| last.max(seen_at)));
+    let changed = (first_seen, last_seen) != (aug_tx.first_seen, aug_tx.last_seen);
+    aug_tx.first_seen = first_seen;
+    aug_tx.last_seen = last_seen;
+    changed
+}
+
+#[derive(Debug, Clone)]
+struct TxOutData<I> {
+    value: u64,
+    index: I,
+    spent_by: Option<(u32, Txid)>,
+}
@@ -0,0 +1,166 @@
+//! Fixtures shared by the tests of the trackers.
+use crate::{BlockTime, CheckPoint, PrevOuts, Update};
+use alloc::vec::Vec;
+use bitcoin::{BlockHash, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+use core::cmp::max;
+
+pub const DESCRIPTOR: &str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL)";
+pub const DERIVABLE_DESCRIPTOR: &str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/0/*)";
+
+/// An input or output of a [`TxSpec`]: `Mine(value, script index)` or `Other(value)`
+pub enum IOSpec {
+    Mine(u64, usize),
+    Other(u64),
+}
+
+/// A tx for [`create_update`] described by whose inputs and outputs it has
+pub struct TxSpec {
+    pub inputs: Vec<IOSpec>,
+    pub outputs: Vec<IOSpec>,
+    pub confirmed_at: Option<u32>,
+    pub is_coinbase: bool,
+}
+
+/// An update with a tx for each of `txs` where `Mine` inputs and outputs use `scripts`.
+pub fn create_update(scripts: Vec<Script>, txs: Vec<TxSpec>, checkpoint_height: u32) -> Update {
+    let last_active_index = txs.iter().fold(None, |lai, tx_spec| {
+        tx_spec
+            .inputs
+            .iter()
+            .chain(tx_spec.outputs.iter())
+            .fold(lai, |lai, spec| match (lai, spec) {
+                (Some(lai), IOSpec::Mine(_, index)) => Some(max(*index as u32, lai)),
+                (None, IOSpec::Mine(_, index)) => Some(*index as u32),
+                _ => lai,
+            })
+    });
+    // every input spends a different outpoint so the txs don't conflict
+    let mut prev_vouts = 0u32..;
+    let transactions = txs
+        .into_iter()
+        .map(|tx_spec| {
+            (
+                match tx_spec.is_coinbase {
+                    false => PrevOuts::Spend(
+                        tx_spec
+                            .inputs
+                            .iter()
+                            .map(|in_spec| match in_spec {
+                                IOSpec::Mine(value, index) => TxOut {
+                                    value: *value,
+                                    script_pubkey: scripts[*index].clone(),
+                                },
+                                IOSpec::Other(value) => TxOut {
+                                    value: *value,
+                                    script_pubkey: Default::default(),
+                                },
+                            })
+                            .collect(),
+                    ),
+                    true => PrevOuts::Coinbase,
+                },
+                Transaction {
+                    version: 1,
+                    lock_time: 0,
+                    input: if tx_spec.is_coinbase {
+                        vec![TxIn::default()]
+                    } else {
+                        tx_spec
+                            .inputs
+                            .iter()
+                            .map(|_| TxIn {
+                                previous_output: OutPoint::new(
+                                    Txid::default(),
+                                    prev_vouts.next().unwrap(),
+                                ),
+                                ..Default::default()
+                            })
+                            .collect()
+                    },
+                    output: tx_spec
+                        .outputs
+                        .into_iter()
+                        .map(|out_spec| match out_spec {
+                            IOSpec::Other(value) => TxOut {
+                                value,
+                                script_pubkey: Script::default(),
+                            },
+                            IOSpec::Mine(value, index) => TxOut {
+                                value,
+                                script_pubkey: scripts[index].clone(),
+                            },
+                        })
+                        .collect(),
+                },
+                tx_spec.confirmed_at.map(|confirmed_at| BlockTime {
+                    height: confirmed_at,
+                    time: confirmed_at as u64,
+                }),
+                None,
+            )
+        })
+        .collect();
+
+    Update {
+        transactions,
+        last_active_index,
+        new_tip: CheckPoint {
+            height: checkpoint_height,
+            hash: BlockHash::default(),
+        },
+        invalidate: Vec::new(),
+        mempool_is_total_set: true,
+        base_tip: None,
+    }
+}
+
+/// A tx spending `previous_outputs` with `output`
+pub fn spending_tx(previous_outputs: &[OutPoint], output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: previous_outputs
+            .iter()
+            .map(|previous_output| TxIn {
+                previous_output: *previous_output,
+                ..Default::default()
+            })
+            .collect(),
+        output,
+    }
+}
+
+/// An update with the txs (and the height they're confirmed at if they are) and a new tip at
+/// `new_tip` which reveals the first script. The previous outputs of the txs aren't ours.
+pub fn update_from_txs(txs: Vec<(Transaction, Option<u32>)>, new_tip: u32) -> Update {
+    Update {
+        transactions: txs
+            .into_iter()
+            .map(|(tx, confirmed_at)| {
+                (
+                    PrevOuts::Spend(vec![TxOut::default(); tx.input.len()]),
+                    tx,
+                    confirmed_at.map(|height| BlockTime {
+                        height,
+                        time: height as u64,
+                    }),
+                    None,
+                )
+            })
+            .collect(),
+        last_active_index: Some(0),
+        new_tip: CheckPoint {
+            height: new_tip,
+            hash: BlockHash::default(),
+        },
+        invalidate: Vec::new(),
+        mempool_is_total_set: true,
+        base_tip: None,
+    }
+}
+
+/// A distinct block hash for each height
+pub fn block_hash(height: u32) -> BlockHash {
+    use bitcoin::hashes::Hash;
+    BlockHash::hash(&height.to_le_bytes())
+}
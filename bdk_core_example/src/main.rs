@@ -8,15 +8,19 @@ use bdk_core::bitcoin::Network;
 use bdk_core::bitcoin::TxIn;
 use bdk_core::bitcoin::TxOut;
 use bdk_core::coin_select::WeightedValue;
-use bdk_core::coin_select::{CoinSelector, CoinSelectorOpt};
+use bdk_core::coin_select::{
+    bnb, knapsack, lowest_fee, single_random_draw, CoinSelector, CoinSelectorOpt, TXIN_BASE_WEIGHT,
+};
 use bdk_core::miniscript::psbt::PsbtInputSatisfier;
 use bdk_core::miniscript::Descriptor;
 use bdk_core::miniscript::DescriptorPublicKey;
-use bdk_core::{DescriptorTracker, MultiTracker};
+use bdk_core::KeychainTracker;
 use bdk_esplora::ureq::{ureq, Client};
 use clap::Parser;
 use clap::Subcommand;
 use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -42,6 +46,13 @@ enum Commands {
         addr_cmd: AddressCmd,
     },
     Balance,
+    /// List the txs affecting the wallet, most recent first
+    History {
+        #[clap(long, default_value = "0")]
+        offset: usize,
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
     Txo {
         #[clap(subcommand)]
         utxo_cmd: TxoCmd,
@@ -62,6 +73,9 @@ pub enum CoinSelectionAlgo {
     OldestFirst,
     NewestFirst,
     BranchAndBound,
+    LowestFee,
+    SingleRandomDraw,
+    Knapsack,
 }
 
 impl Default for CoinSelectionAlgo {
@@ -81,6 +95,9 @@ impl core::str::FromStr for CoinSelectionAlgo {
             "oldest-first" => OldestFirst,
             "newest-first" => NewestFirst,
             "bnb" => BranchAndBound,
+            "lowest-fee" => LowestFee,
+            "single-random-draw" => SingleRandomDraw,
+            "knapsack" => Knapsack,
             unknown => return Err(anyhow!("unknown coin selection algorithm '{}'", unknown)),
         })
     }
@@ -98,6 +115,9 @@ impl core::fmt::Display for CoinSelectionAlgo {
                 OldestFirst => "oldest-first",
                 NewestFirst => "newest-first",
                 BranchAndBound => "bnb",
+                LowestFee => "lowest-fee",
+                SingleRandomDraw => "single-random-draw",
+                Knapsack => "knapsack",
             }
         )
     }
@@ -118,29 +138,57 @@ pub enum TxoCmd {
     List,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Keychain {
+    External,
+    Internal,
+}
+
+impl core::fmt::Display for Keychain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Keychain::External => write!(f, "external"),
+            Keychain::Internal => write!(f, "internal"),
+        }
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let secp = Secp256k1::default();
-    let (descriptor, keymap) =
-        Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &args.descriptor)?;
-    // external tracker
-    let tracker = DescriptorTracker::new(descriptor);
-    let change_tracker = args
-        .change_descriptor
-        .map(|descriptor| {
-            let (descriptor, _key_map) =
-                Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &descriptor)?;
-            Ok::<_, bdk_core::miniscript::Error>(DescriptorTracker::new(descriptor))
-        })
-        .transpose()?;
-
-    // trackers are in a Vec<DescriptorTracker> which has methods attached to it via extension traits
-    // 0 -> external tacker
-    // 1 -> internal tracker
-    let mut trackers = vec![tracker];
-    if let Some(change_tracker) = change_tracker {
-        trackers.push(change_tracker);
-    }
+    // a multipath descriptor like wpkh(xpub/<0;1>/*) gives us both keychains
+    let (mut tracker, keymap) = if args.descriptor.contains('<') {
+        if args.change_descriptor.is_some() {
+            return Err(anyhow!(
+                "a change descriptor can't be set along with a multipath descriptor"
+            ));
+        }
+        KeychainTracker::from_multipath(
+            &secp,
+            &args.descriptor,
+            Keychain::External,
+            Keychain::Internal,
+        )?
+    } else {
+        let (descriptor, keymap) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &args.descriptor)?;
+        let mut keychains = vec![(Keychain::External, descriptor)];
+        if let Some(change_descriptor) = &args.change_descriptor {
+            let (change_descriptor, _key_map) =
+                Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, change_descriptor)?;
+            keychains.push((Keychain::Internal, change_descriptor));
+        }
+        let tracker = KeychainTracker::new(keychains)
+            .map_err(|(keychain, e)| anyhow::anyhow!("{} descriptor: {}", keychain, e))?;
+        (tracker, keymap)
+    };
+    let has_change_descriptor = tracker.descriptor(&Keychain::Internal).is_some();
+    // without a change descriptor change goes back to the external keychain
+    let change_keychain = if has_change_descriptor {
+        Keychain::Internal
+    } else {
+        Keychain::External
+    };
 
     let esplora_url = match args.network {
         Network::Bitcoin => "https://mempool.space/api",
@@ -152,22 +200,13 @@ fn main() -> anyhow::Result<()> {
     let mut client = Client::new(ureq::Agent::new(), esplora_url);
     client.parallel_requests = 5;
 
-    for (i, tracker) in trackers.iter_mut().enumerate() {
-        fully_sync_tracker(
-            match i {
-                1 => "internal",
-                _ => "external",
-            },
-            &client,
-            tracker,
-        )?;
-    }
+    fully_sync_tracker(&client, &mut tracker)?;
 
     match args.command {
         Commands::Address { addr_cmd } => {
             let new_address = match addr_cmd {
-                AddressCmd::Next => Some(trackers[0].derive_next_unused()),
-                AddressCmd::New => Some(trackers[0].derive_new()),
+                AddressCmd::Next => Some(tracker.derive_next_unused(&Keychain::External).0),
+                AddressCmd::New => Some(tracker.derive_new(&Keychain::External).0),
                 _ => None,
             };
 
@@ -181,49 +220,58 @@ fn main() -> anyhow::Result<()> {
             match addr_cmd {
                 AddressCmd::Next | AddressCmd::New => { /* covered */ }
                 AddressCmd::List { change } => {
-                    let tracker = if change {
-                        trackers
-                            .get(1)
-                            .ok_or(anyhow!("you havent set a change descriptor"))?
+                    let keychain = if change {
+                        if !has_change_descriptor {
+                            return Err(anyhow!("you havent set a change descriptor"));
+                        }
+                        Keychain::Internal
                     } else {
-                        &trackers[0]
+                        Keychain::External
                     };
 
-                    for (i, spk) in tracker.iter_derived_scripts().enumerate() {
-                        let address = Address::from_script(&spk, args.network)
+                    for (i, spk) in tracker.iter_derived_scripts(&keychain).enumerate() {
+                        let address = Address::from_script(spk, args.network)
                             .expect("should always be able to derive address");
-                        println!("{} used:{}", address, tracker.is_used(i as u32));
+                        println!("{} used:{}", address, tracker.is_used(&keychain, i as u32));
                     }
                 }
             }
         }
         Commands::Balance => {
-            let utxos = trackers
-                .iter_unspent()
-                .map(|(tracker_id, utxo)| (tracker_id == 1, utxo));
-            let (confirmed, unconfirmed) =
-                utxos.fold((0, 0), |(confirmed, unconfirmed), (is_change, utxo)| {
-                    if utxo.confirmed_at.is_some() || is_change {
-                        (confirmed + utxo.value, unconfirmed)
-                    } else {
-                        (confirmed, unconfirmed + utxo.value)
-                    }
-                });
+            // we trust our own change
+            let balance = tracker.balance(|keychain| *keychain == Keychain::Internal);
 
-            println!("confirmed: {}", confirmed);
-            println!("unconfirmed: {}", unconfirmed);
+            println!("confirmed: {}", balance.confirmed);
+            println!("trusted pending: {}", balance.trusted_pending);
+            println!("untrusted pending: {}", balance.untrusted_pending);
+            println!("immature: {}", balance.immature);
+        }
+        Commands::History { offset, limit } => {
+            for entry in tracker.history(offset, limit) {
+                let status = match entry.confirmation_time {
+                    Some(time) => format!("confirmed:{}", time.height),
+                    None => "unconfirmed".to_string(),
+                };
+                println!(
+                    "{} {:+} fee:{} {}",
+                    entry.txid,
+                    entry.net_value().as_sat(),
+                    entry.fee.as_sat(),
+                    status
+                );
+            }
         }
         Commands::Txo { utxo_cmd } => match utxo_cmd {
             TxoCmd::List => {
-                for (tracker_index, txout) in trackers.iter_txout() {
-                    let script = trackers[tracker_index]
-                        .script_at_index(txout.derivation_index)
+                for (keychain, txout) in tracker.iter_txout() {
+                    let script = tracker
+                        .script_at_index(&keychain, txout.derivation_index)
                         .unwrap();
                     let address = Address::from_script(script, args.network).unwrap();
 
                     println!(
                         "keychain:{} {} {} {} spent:{:?}",
-                        tracker_index, txout.value, txout.outpoint, address, txout.spent_by
+                        keychain, txout.value, txout.outpoint, address, txout.spent_by
                     )
                 }
             }
@@ -233,7 +281,14 @@ fn main() -> anyhow::Result<()> {
             address,
             coin_select,
         } => {
-            let mut candidates = trackers.iter_unspent().collect::<Vec<_>>();
+            // only coins that a tx in the next block can spend (i.e. no immature coinbase outputs)
+            let next_height = tracker
+                .latest_blockheight()
+                .map(|height| height + 1)
+                .unwrap_or(0);
+            let mut candidates = tracker
+                .iter_spendable_unspent(next_height)
+                .collect::<Vec<_>>();
 
             // apply coin selection algorithm
             match coin_select {
@@ -253,15 +308,23 @@ fn main() -> anyhow::Result<()> {
                             .unwrap_or(u32::MAX),
                     )
                 }),
-                CoinSelectionAlgo::BranchAndBound => todo!(),
+                // these choose the order themselves but this is the order we fall back to
+                CoinSelectionAlgo::BranchAndBound
+                | CoinSelectionAlgo::LowestFee
+                | CoinSelectionAlgo::SingleRandomDraw
+                | CoinSelectionAlgo::Knapsack => {
+                    candidates.sort_by_key(|(_, utxo)| Reverse(utxo.value))
+                }
             }
 
             // turn the txos we chose into a weight and value
             let wv_candidates = candidates
                 .iter()
-                .map(|(tracker_index, utxo)| WeightedValue {
+                .map(|(keychain, utxo)| WeightedValue {
                     value: utxo.value,
-                    weight: trackers[*tracker_index].max_satisfaction_weight(),
+                    weight: tracker
+                        .max_satisfaction_weight(keychain)
+                        .expect("the keychain exists"),
                 })
                 .collect();
 
@@ -270,34 +333,59 @@ fn main() -> anyhow::Result<()> {
                 script_pubkey: address.script_pubkey(),
             }];
 
-            // apply coin selection by saying we need to fund these outputs
+            let change_satisfaction_weight = tracker
+                .max_satisfaction_weight(&change_keychain)
+                .expect("the keychain exists");
+            // apply coin selection by saying we need to fund these outputs. Change worth less than
+            // the dust value is given up as fees.
             let mut coin_selector = CoinSelector::new(
                 wv_candidates,
-                CoinSelectorOpt::fund_outputs(&outputs, trackers[1].max_satisfaction_weight()),
+                CoinSelectorOpt {
+                    drain_spend_weight: change_satisfaction_weight + TXIN_BASE_WEIGHT,
+                    min_drain_value: tracker
+                        .dust_value(&change_keychain)
+                        .expect("the keychain exists"),
+                    ..CoinSelectorOpt::fund_outputs(&outputs, change_satisfaction_weight)
+                },
             );
 
-            // just select coins in the order provided until we have enough
-            let selection = match coin_selector.select_until_finished() {
-                Some(selection) => selection,
-                None => {
-                    return Err(anyhow!(
-                        "Insufficient funds. Needed {} had {}",
-                        value,
-                        coin_selector.current_value()
-                    ))
-                }
+            // random numbers from hashing a counter with std's randomly keyed hasher
+            let random_state = RandomState::new();
+            let mut counter = 0_u64;
+            let mut rng = || {
+                counter += 1;
+                random_state.hash_one(counter)
+            };
+
+            // use one of the coin selection algorithms if asked to. Otherwise (or if it doesn't
+            // find a selection) just select coins in the order provided until we have enough.
+            let searched_selection = match coin_select {
+                CoinSelectionAlgo::BranchAndBound => bnb(&coin_selector, 100_000),
+                CoinSelectionAlgo::LowestFee => lowest_fee(&coin_selector, 100_000),
+                CoinSelectionAlgo::SingleRandomDraw => single_random_draw(&coin_selector, &mut rng),
+                CoinSelectionAlgo::Knapsack => knapsack(&coin_selector, &mut rng, 1_000),
+                _ => None,
             };
+            let selection =
+                match searched_selection.or_else(|| coin_selector.select_until_finished()) {
+                    Some(selection) => selection,
+                    None => {
+                        return Err(anyhow!(
+                            "Insufficient funds. Needed {} had {}",
+                            value,
+                            coin_selector.current_value()
+                        ))
+                    }
+                };
 
             // get the selected utxos
             let selected_txos = selection.apply_selection(&candidates).collect::<Vec<_>>();
 
-            let change_tracker = trackers.last_mut().unwrap();
-
-            if selection.use_change && selection.excess >= change_tracker.dust_value() {
-                // if the selection tells us to use change and the change value is sufficient we add it as an output
+            if selection.use_change {
+                // if the selection tells us to use change we add it as an output
                 outputs.push(TxOut {
                     value: selection.excess,
-                    script_pubkey: change_tracker.derive_new().1.clone(),
+                    script_pubkey: tracker.derive_new(&change_keychain).0 .1.clone(),
                 })
             }
 
@@ -307,7 +395,7 @@ fn main() -> anyhow::Result<()> {
             // spk of each selected input. We will need the descriptors to build the witness
             // properly with ".satisfy".
             let (mut psbt, definite_descriptors) =
-                trackers.create_psbt(selected_txos.iter().map(|(_, txo)| txo.outpoint), outputs);
+                tracker.create_psbt(selected_txos.iter().map(|(_, txo)| txo.outpoint), outputs);
 
             let cache_tx = psbt.unsigned_tx.clone();
             let mut sighash_cache = SighashCache::new(&cache_tx);
@@ -372,28 +460,31 @@ fn main() -> anyhow::Result<()> {
 }
 
 pub fn fully_sync_tracker(
-    name: &str,
     client: &Client,
-    tracker: &mut DescriptorTracker,
+    tracker: &mut KeychainTracker<Keychain>,
 ) -> anyhow::Result<()> {
     let start = std::time::Instant::now();
-    eprint!("scanning {} addresses indexes ", name);
+    let scripts = tracker
+        .iter_scripts()
+        .into_iter()
+        .map(|(keychain, scripts)| {
+            let mut first = true;
+            let scripts = scripts.inspect(move |(i, _)| {
+                use std::io::{self, Write};
+                if first {
+                    eprint!("\nscanning {} addresses indexes ", keychain);
+                    first = false;
+                }
+                eprint!("{} ", i);
+                let _ = io::stdout().flush();
+            });
+            (keychain, scripts)
+        })
+        .collect();
     let update = client
-        .fetch_related_transactions(
-            tracker
-                .iter_scripts()
-                .enumerate()
-                .map(|(i, script)| (i as u32, script))
-                .inspect(|(i, _)| {
-                    use std::io::{self, Write};
-                    eprint!("{} ", i);
-                    let _ = io::stdout().flush();
-                }),
-            2,
-            core::iter::empty(),
-        )
+        .fetch_related_transactions_for_keychains(scripts, 2, tracker.block_locator())
         .context("fetching transactions")?;
-    eprintln!("success! ({}ms)", start.elapsed().as_millis());
-    tracker.apply_update(update);
+    eprintln!("\nsuccess! ({}ms)", start.elapsed().as_millis());
+    tracker.apply_update(update).context("applying update")?;
     Ok(())
 }
@@ -5,7 +5,11 @@ use bdk_core::{
         hashes::{hex::ToHex, sha256, Hash},
         BlockHash, Script, Txid, Transaction,
     },
-    CheckPoint, Update,
+    BlockTime, CheckPoint, KeychainUpdate, PrevOuts, Update,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use ureq::Agent;
 pub use ureq;
@@ -156,29 +160,106 @@ impl Client {
         Ok(())
     }
 
-    /// TODO
+    /// Fetches the txs of `scripts` until `stop_gap` scripts in a row have none.
+    ///
+    /// `locator` is the tracker's [`block_locator`] which is used to find the update's base tip
+    /// and the checkpoints that a reorg has invalidated.
+    ///
+    /// [`block_locator`]: bdk_core::DescriptorTracker::block_locator
     pub fn fetch_related_transactions(
         &self,
-        mut scripts: impl Iterator<Item = (u32, Script)>,
+        scripts: impl Iterator<Item = (u32, Script)>,
         stop_gap: usize,
-        known_tips: impl Iterator<Item = CheckPoint>,
+        locator: impl IntoIterator<Item = CheckPoint>,
     ) -> Result<Update, UpdateError> {
-        let mut empty_scripts = 0;
-        let mut transactions = vec![];
-        let mut last_active_index = None;
-        let mut invalidate = None;
+        let (base_tip, invalidate) = self.find_base_tip(locator)?;
+        let new_tip = self.tip()?;
+        let mut transactions = RelatedTransactions::default();
+        let last_active_index = self.scan_scripts(scripts, stop_gap, &mut transactions)?;
+
+        if self.tip_hash()? != new_tip.hash {
+            return Err(UpdateError::TipChangeDuringUpdate);
+        }
+
+        let update = Update {
+            transactions: transactions.txs,
+            mempool_is_total_set: true,
+            last_active_index,
+            base_tip,
+            invalidate,
+            new_tip,
+        };
+
+        Ok(update)
+    }
+
+    /// Like [`fetch_related_transactions`] but scans the scripts of several keychains against the
+    /// same tip so the result can be applied to a [`KeychainTracker`] in one go.
+    ///
+    /// [`fetch_related_transactions`]: Self::fetch_related_transactions
+    /// [`KeychainTracker`]: bdk_core::KeychainTracker
+    pub fn fetch_related_transactions_for_keychains<K: Ord + Clone>(
+        &self,
+        scripts: BTreeMap<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        locator: impl IntoIterator<Item = CheckPoint>,
+    ) -> Result<KeychainUpdate<K>, UpdateError> {
+        let (base_tip, invalidate) = self.find_base_tip(locator)?;
+        let new_tip = self.tip()?;
+        let mut transactions = RelatedTransactions::default();
+        let mut last_active_index = BTreeMap::new();
+
+        for (keychain, scripts) in scripts {
+            if let Some(index) = self.scan_scripts(scripts, stop_gap, &mut transactions)? {
+                last_active_index.insert(keychain, index);
+            }
+        }
+
+        if self.tip_hash()? != new_tip.hash {
+            return Err(UpdateError::TipChangeDuringUpdate);
+        }
+
+        Ok(KeychainUpdate {
+            transactions: transactions.txs,
+            mempool_is_total_set: true,
+            last_active_index,
+            base_tip,
+            invalidate,
+            new_tip,
+        })
+    }
+
+    /// Finds the first checkpoint of `locator` that is still in the chain. Returns it along with
+    /// the ones before it that aren't.
+    fn find_base_tip(
+        &self,
+        locator: impl IntoIterator<Item = CheckPoint>,
+    ) -> Result<(Option<CheckPoint>, Vec<CheckPoint>), UpdateError> {
+        let mut invalidate = Vec::new();
         let mut base_tip = None;
 
-        for tip in known_tips {
-            if self.is_block_present(tip)? {
-                base_tip = Some(tip);
+        for checkpoint in locator {
+            if self.is_block_present(checkpoint)? {
+                base_tip = Some(checkpoint);
                 break;
             } else {
-                invalidate = Some(tip);
+                invalidate.push(checkpoint);
             }
         }
 
-        let new_tip = self.tip()?;
+        Ok((base_tip, invalidate))
+    }
+
+    /// Fetches the txs of `scripts` until `stop_gap` scripts in a row have none. Returns the index
+    /// of the last script that had txs.
+    fn scan_scripts(
+        &self,
+        mut scripts: impl Iterator<Item = (u32, Script)>,
+        stop_gap: usize,
+        transactions: &mut RelatedTransactions,
+    ) -> Result<Option<u32>, UpdateError> {
+        let mut empty_scripts = 0;
+        let mut last_active_index = None;
 
         loop {
             let handles = (0..self.parallel_requests)
@@ -223,11 +304,7 @@ impl Client {
                     empty_scripts = 0;
                 }
                 for tx in related_txs {
-                    transactions.push((
-                        tx.previous_outputs(),
-                        tx.to_tx(),
-                        tx.status.to_block_time(),
-                    ))
+                    transactions.push(tx);
                 }
             }
 
@@ -236,19 +313,37 @@ impl Client {
             }
         }
 
-        if self.tip_hash()? != new_tip.hash {
-            return Err(UpdateError::TipChangeDuringUpdate);
-        }
+        Ok(last_active_index)
+    }
+}
 
-        let update = Update {
-            transactions,
-            mempool_is_total_set: true,
-            last_active_index,
-            base_tip,
-            invalidate,
-            new_tip,
-        };
+/// The txs found while scanning. A tx related to more than one script is only included once.
+#[derive(Default)]
+struct RelatedTransactions {
+    seen: HashSet<Txid>,
+    txs: Vec<(PrevOuts, Transaction, Option<BlockTime>, Option<u64>)>,
+}
 
-        Ok(update)
+impl RelatedTransactions {
+    fn push(&mut self, tx: Tx) {
+        if self.seen.insert(tx.txid) {
+            let confirmation_time = tx.status.to_block_time();
+            // esplora doesn't tell us when it first saw an unconfirmed tx so we say we saw it now
+            let seen_at = match confirmation_time {
+                Some(_) => None,
+                None => Some(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("system time is after the unix epoch")
+                        .as_secs(),
+                ),
+            };
+            self.txs.push((
+                tx.previous_outputs(),
+                tx.to_tx(),
+                confirmation_time,
+                seen_at,
+            ))
+        }
     }
 }
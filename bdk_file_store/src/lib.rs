@@ -0,0 +1,333 @@
+//! An append-only flat file store for [`DescriptorTracker`] changesets.
+//!
+//! The file starts with [`MAGIC_BYTES`] and is followed by entries which look like:
+//!
+//! - the length of the payload as a little endian `u32`
+//! - the first four bytes of the sha256 of the length
+//! - the first four bytes of the sha256 of the payload
+//! - the payload which is a bincode encoded [`ChangeSet`]
+use bdk_core::{
+    bitcoin::hashes::{sha256, Hash},
+    ChangeSet, DescriptorTracker,
+};
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// The bytes every store file starts with. The last byte is the version of the format.
+pub const MAGIC_BYTES: [u8; 8] = *b"bdkfs\x00\x00\x03";
+
+const ENTRY_HEADER_LEN: usize = 4 + 4 + 4;
+
+/// Persists the [`ChangeSet`]s of a [`DescriptorTracker`] by appending them to a file.
+#[derive(Debug)]
+pub struct FileStore {
+    db_file: File,
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// Opens the store at `path` (creating it if it doesn't exist) and applies the changesets in it
+    /// to `tracker` in the order they were appended.
+    ///
+    /// `tracker` should be a new tracker with the same descriptor as the one whose changesets were
+    /// stored. If the last entry was only partially written (e.g. we crashed while appending it) it
+    /// is truncated from the file.
+    pub fn load(path: impl AsRef<Path>, tracker: &mut DescriptorTracker) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut db_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut bytes = Vec::new();
+        db_file.read_to_end(&mut bytes)?;
+
+        if bytes.is_empty() {
+            db_file.write_all(&MAGIC_BYTES)?;
+            db_file.sync_all()?;
+            return Ok(Self { db_file, path });
+        }
+
+        if !bytes.starts_with(&MAGIC_BYTES) {
+            return Err(Error::InvalidMagicBytes);
+        }
+
+        let mut pos = MAGIC_BYTES.len();
+        while pos < bytes.len() {
+            match read_entry(&bytes[pos..]) {
+                Entry::Complete(payload) => {
+                    let changeset = bincode::deserialize(payload)?;
+                    tracker.apply_changeset(changeset);
+                    pos += ENTRY_HEADER_LEN + payload.len();
+                }
+                Entry::Torn => {
+                    db_file.set_len(pos as u64)?;
+                    db_file.sync_all()?;
+                    break;
+                }
+                Entry::Corrupted => return Err(Error::Corrupted { offset: pos as u64 }),
+            }
+        }
+
+        Ok(Self { db_file, path })
+    }
+
+    /// Appends a changeset to the store.
+    ///
+    /// Changesets must be appended in the order they were produced by the tracker.
+    pub fn append_changeset(&mut self, changeset: &ChangeSet) -> Result<(), Error> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+
+        let entry = encode_entry(changeset)?;
+        self.db_file.seek(SeekFrom::End(0))?;
+        self.db_file.write_all(&entry)?;
+        self.db_file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the history of changesets in the store with a single entry that recreates the
+    /// state of `tracker`.
+    ///
+    /// `tracker` must be in the state you get from loading the store and applying all the
+    /// changesets appended since.
+    pub fn compact(&mut self, tracker: &DescriptorTracker) -> Result<(), Error> {
+        let mut tmp_path = OsString::from(&self.path);
+        tmp_path.push(".tmp");
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&MAGIC_BYTES)?;
+        tmp_file.write_all(&encode_entry(&tracker.full_changeset())?)?;
+        tmp_file.sync_all()?;
+        // the rename is atomic so if we crash we either have the old history or the snapshot
+        std::fs::rename(&tmp_path, &self.path)?;
+        // but it's only durable once the directory it happened in is synced too
+        #[cfg(unix)]
+        File::open(parent_dir(&self.path))?.sync_all()?;
+
+        self.db_file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// The directory `path` is in
+#[cfg(unix)]
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+enum Entry<'a> {
+    Complete(&'a [u8]),
+    /// The entry runs past the end of the file (according to a length that passes its checksum)
+    /// or is the last one and fails its checksum
+    Torn,
+    Corrupted,
+}
+
+fn read_entry(bytes: &[u8]) -> Entry<'_> {
+    if bytes.len() < ENTRY_HEADER_LEN {
+        return Entry::Torn;
+    }
+    // a corrupted length could make any entry look like it runs past the end of the file
+    if checksum(&bytes[..4]) != bytes[4..8] {
+        return Entry::Corrupted;
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let end = ENTRY_HEADER_LEN + len;
+    if bytes.len() < end {
+        return Entry::Torn;
+    }
+
+    let payload = &bytes[ENTRY_HEADER_LEN..end];
+    if checksum(payload) != bytes[8..ENTRY_HEADER_LEN] {
+        if bytes.len() == end {
+            return Entry::Torn;
+        }
+        return Entry::Corrupted;
+    }
+
+    Entry::Complete(payload)
+}
+
+fn encode_entry(changeset: &ChangeSet) -> Result<Vec<u8>, Error> {
+    let payload = bincode::serialize(changeset)?;
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + payload.len());
+    let len = (payload.len() as u32).to_le_bytes();
+    entry.extend(len);
+    entry.extend(checksum(&len));
+    entry.extend(checksum(&payload));
+    entry.extend(payload);
+    Ok(entry)
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 4] {
+    let hash = sha256::Hash::hash(bytes);
+    hash[..4].try_into().unwrap()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file doesn't start with [`MAGIC_BYTES`]
+    InvalidMagicBytes,
+    /// An entry that isn't the last one failed its checksum or the length of an entry did
+    Corrupted {
+        offset: u64,
+    },
+    Bincode(bincode::Error),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidMagicBytes => write!(f, "The file is not a tracker store"),
+            Error::Corrupted { offset } => write!(f, "The entry at offset {} is corrupted", offset),
+            Error::Bincode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Bincode(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk_core::{
+        bitcoin::{BlockHash, Transaction, TxOut},
+        CheckPoint, PrevOuts, Update, UpdateResult,
+    };
+
+    const DESCRIPTOR: &str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL)";
+
+    fn new_tracker() -> DescriptorTracker {
+        DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap()
+    }
+
+    /// Applies an update with a single tx paying to the tracker's first script and returns the
+    /// changeset.
+    fn apply_tx(tracker: &mut DescriptorTracker, height: u32) -> ChangeSet {
+        let tx = Transaction {
+            version: 1,
+            lock_time: height,
+            input: vec![Default::default()],
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: tracker.iter_scripts().next().unwrap(),
+            }],
+        };
+        let update = Update {
+            transactions: vec![(PrevOuts::Coinbase, tx, None, None)],
+            last_active_index: Some(0),
+            mempool_is_total_set: false,
+            base_tip: tracker.latest_checkpoint(),
+            invalidate: Vec::new(),
+            new_tip: CheckPoint {
+                height,
+                hash: BlockHash::default(),
+            },
+        };
+        match tracker.apply_update(update) {
+            Ok(UpdateResult::Ok(changeset)) => changeset,
+            res => panic!("unexpected update result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn torn_final_write_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tracker.db");
+        let mut tracker = new_tracker();
+        let mut store = FileStore::load(&path, &mut new_tracker()).unwrap();
+
+        store.append_changeset(&apply_tx(&mut tracker, 1)).unwrap();
+        let good_len = std::fs::metadata(&path).unwrap().len();
+        let mut last = tracker.clone();
+        store.append_changeset(&apply_tx(&mut last, 2)).unwrap();
+
+        // chop off the end of the last entry
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(std::fs::metadata(&path).unwrap().len() - 3)
+            .unwrap();
+
+        let mut loaded = new_tracker();
+        FileStore::load(&path, &mut loaded).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), good_len);
+        assert_eq!(
+            loaded.iter_tx().collect::<Vec<_>>(),
+            tracker.iter_tx().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn corrupted_length_is_not_mistaken_for_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tracker.db");
+        let mut tracker = new_tracker();
+        let mut store = FileStore::load(&path, &mut new_tracker()).unwrap();
+        store.append_changeset(&apply_tx(&mut tracker, 1)).unwrap();
+        store.append_changeset(&apply_tx(&mut tracker, 2)).unwrap();
+
+        // make the first entry claim to run past the end of the file
+        let mut bytes = std::fs::read(&path).unwrap();
+        let len_pos = MAGIC_BYTES.len();
+        bytes[len_pos..len_pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        match FileStore::load(&path, &mut new_tracker()) {
+            Err(Error::Corrupted { offset }) => assert_eq!(offset, len_pos as u64),
+            res => panic!("unexpected load result {:?}", res),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn compaction_keeps_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tracker.db");
+        let mut tracker = new_tracker();
+        let mut store = FileStore::load(&path, &mut new_tracker()).unwrap();
+
+        for height in 1..5 {
+            store
+                .append_changeset(&apply_tx(&mut tracker, height))
+                .unwrap();
+        }
+        store.compact(&tracker).unwrap();
+        store.append_changeset(&apply_tx(&mut tracker, 5)).unwrap();
+
+        let mut loaded = new_tracker();
+        FileStore::load(&path, &mut loaded).unwrap();
+        assert_eq!(
+            loaded.iter_tx().collect::<Vec<_>>(),
+            tracker.iter_tx().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.latest_checkpoint(), tracker.latest_checkpoint());
+        assert_eq!(
+            loaded.next_derivation_index(),
+            tracker.next_derivation_index()
+        );
+    }
+}
@@ -0,0 +1,736 @@
+//! A SQLite store for [`DescriptorTracker`]s.
+//!
+//! Everything a tracker holds is stored in normalized tables so it can be queried with plain SQL.
+//! Hashes are stored as hex strings in the order they're usually displayed in while transactions
+//! and scripts are stored as consensus encoded blobs.
+//!
+//! The `txouts` table and the `mempool` view are derived from the txs and scripts so they're only
+//! there to be queried. Loading checks that `txouts` still agrees with the txs.
+use bdk_core::{
+    bitcoin::{consensus, hashes::hex, BlockHash, OutPoint, Transaction, Txid},
+    AugmentedTx, BlockTime, ChangeSet, DescriptorTracker, FeeRate,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction as DbTransaction};
+use std::{collections::BTreeMap, path::Path, str::FromStr};
+
+/// The migrations that bring the schema from the version at their index to the next one.
+///
+/// Never change a migration that has been released. Add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE descriptors (
+    id INTEGER PRIMARY KEY,
+    descriptor TEXT NOT NULL UNIQUE,
+    next_derivation_index INTEGER NOT NULL,
+    latest_blockheight INTEGER
+);
+CREATE TABLE scripts (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    derivation_index INTEGER NOT NULL,
+    script BLOB NOT NULL,
+    PRIMARY KEY (descriptor_id, derivation_index)
+);
+CREATE TABLE checkpoints (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    height INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (descriptor_id, height)
+);
+CREATE TABLE txs (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    txid TEXT NOT NULL,
+    tx BLOB NOT NULL,
+    fee INTEGER NOT NULL,
+    feerate REAL NOT NULL,
+    confirmation_height INTEGER,
+    confirmation_time INTEGER,
+    checkpoint_height INTEGER,
+    PRIMARY KEY (descriptor_id, txid)
+);
+CREATE TABLE txouts (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    derivation_index INTEGER NOT NULL,
+    spent_by_txid TEXT,
+    spent_by_vin INTEGER,
+    PRIMARY KEY (descriptor_id, txid, vout)
+);
+CREATE TABLE mempool (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    txid TEXT NOT NULL,
+    PRIMARY KEY (descriptor_id, txid)
+);
+CREATE TABLE evicted (
+    descriptor_id INTEGER NOT NULL REFERENCES descriptors(id),
+    txid TEXT NOT NULL,
+    replaced_by TEXT NOT NULL,
+    PRIMARY KEY (descriptor_id, txid)
+);",
+    "ALTER TABLE txs ADD COLUMN is_coinbase INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE txs ADD COLUMN first_seen INTEGER;
+ALTER TABLE txs ADD COLUMN last_seen INTEGER;",
+    "ALTER TABLE txs ADD COLUMN feerate_sat_per_kvb INTEGER NOT NULL DEFAULT 0;
+UPDATE txs SET feerate_sat_per_kvb = CAST(ROUND(feerate * 1000) AS INTEGER);
+ALTER TABLE txs DROP COLUMN feerate;",
+    "ALTER TABLE descriptors ADD COLUMN lookahead INTEGER NOT NULL DEFAULT 0;",
+    "DROP TABLE mempool;
+CREATE VIEW mempool AS SELECT descriptor_id, txid FROM txs WHERE confirmation_height IS NULL;",
+];
+
+/// The tables that hold the state of a single descriptor
+const DESCRIPTOR_TABLES: &[&str] = &["scripts", "checkpoints", "txs", "txouts", "evicted"];
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path` (creating it if it doesn't exist) and migrates it to the
+    /// latest schema.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, Error> {
+        let db_tx = conn.transaction()?;
+        migrate(&db_tx)?;
+        db_tx.commit()?;
+        Ok(Self { conn })
+    }
+
+    /// The version of the schema the database is at
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        Ok(schema_version(&self.conn)?)
+    }
+
+    /// Replaces whatever is stored for the tracker's descriptor with the tracker's current state.
+    pub fn save(&mut self, tracker: &DescriptorTracker) -> Result<(), Error> {
+        let db_tx = self.conn.transaction()?;
+        let changeset = tracker.full_changeset();
+
+        db_tx.execute(
+            "INSERT INTO descriptors (descriptor, next_derivation_index, latest_blockheight, lookahead)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (descriptor) DO UPDATE SET
+                 next_derivation_index = excluded.next_derivation_index,
+                 latest_blockheight = excluded.latest_blockheight,
+                 lookahead = excluded.lookahead",
+            params![
+                tracker.descriptor().to_string(),
+                tracker.next_derivation_index(),
+                tracker.latest_blockheight(),
+                tracker.lookahead()
+            ],
+        )?;
+        let descriptor_id = descriptor_id(&db_tx, tracker)?.expect("we just inserted it");
+
+        for table in DESCRIPTOR_TABLES {
+            db_tx.execute(
+                &format!("DELETE FROM {} WHERE descriptor_id = ?1", table),
+                [descriptor_id],
+            )?;
+        }
+
+        let mut index = 0;
+        while let Some(script) = tracker.script_at_index(index) {
+            db_tx.execute(
+                "INSERT INTO scripts (descriptor_id, derivation_index, script) VALUES (?1, ?2, ?3)",
+                params![descriptor_id, index, script.as_bytes()],
+            )?;
+            index += 1;
+        }
+
+        let mut checkpoint_of_tx = BTreeMap::new();
+        for (height, (hash, txids)) in &changeset.new_checkpoints {
+            db_tx.execute(
+                "INSERT INTO checkpoints (descriptor_id, height, hash) VALUES (?1, ?2, ?3)",
+                params![descriptor_id, height, hash.to_string()],
+            )?;
+            checkpoint_of_tx.extend(txids.iter().map(|txid| (*txid, *height)));
+        }
+
+        for (txid, aug_tx) in &changeset.added_txs {
+            db_tx.execute(
+                "INSERT INTO txs (descriptor_id, txid, tx, fee, feerate_sat_per_kvb, confirmation_height,
+                     confirmation_time, checkpoint_height, is_coinbase, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    descriptor_id,
+                    txid.to_string(),
+                    consensus::serialize(&aug_tx.tx),
+                    aug_tx.fee as i64,
+                    aug_tx.feerate.as_sat_per_kvb() as i64,
+                    aug_tx.confirmation_time.map(|time| time.height),
+                    aug_tx.confirmation_time.map(|time| time.time as i64),
+                    checkpoint_of_tx.get(txid),
+                    aug_tx.is_coinbase,
+                    aug_tx.first_seen.map(|time| time as i64),
+                    aug_tx.last_seen.map(|time| time as i64),
+                ],
+            )?;
+        }
+
+        for txout in tracker.iter_txout() {
+            db_tx.execute(
+                "INSERT INTO txouts (descriptor_id, txid, vout, value, derivation_index,
+                     spent_by_txid, spent_by_vin)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    descriptor_id,
+                    txout.outpoint.txid.to_string(),
+                    txout.outpoint.vout,
+                    txout.value as i64,
+                    txout.derivation_index,
+                    txout.spent_by.map(|(_, txid)| txid.to_string()),
+                    txout.spent_by.map(|(vin, _)| vin),
+                ],
+            )?;
+        }
+
+        for (txid, replaced_by) in &changeset.evicted_txs {
+            db_tx.execute(
+                "INSERT INTO evicted (descriptor_id, txid, replaced_by) VALUES (?1, ?2, ?3)",
+                params![descriptor_id, txid.to_string(), replaced_by.to_string()],
+            )?;
+        }
+
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    /// Loads the state stored for the tracker's descriptor into it.
+    ///
+    /// `tracker` should be a new tracker. It is left untouched if nothing has been stored for its
+    /// descriptor or if the stored txouts don't match the ones derived from the stored txs.
+    pub fn load_into_tracker(&mut self, tracker: &mut DescriptorTracker) -> Result<(), Error> {
+        let db_tx = self.conn.transaction()?;
+        let descriptor_id = match descriptor_id(&db_tx, tracker)? {
+            Some(descriptor_id) => descriptor_id,
+            None => return Ok(()),
+        };
+        let mut changeset: ChangeSet = ChangeSet::default();
+        let mut stored_txouts = BTreeMap::new();
+
+        let (next_derivation_index, latest_blockheight, lookahead) = db_tx.query_row(
+            "SELECT next_derivation_index, latest_blockheight, lookahead FROM descriptors
+             WHERE id = ?1",
+            [descriptor_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        changeset.scripts.next_derivation_index = Some(next_derivation_index);
+        changeset.scripts.lookahead = Some(lookahead);
+        changeset.latest_blockheight = latest_blockheight;
+        changeset.scripts.last_stored_index = db_tx.query_row(
+            "SELECT MAX(derivation_index) FROM scripts WHERE descriptor_id = ?1",
+            [descriptor_id],
+            |row| row.get(0),
+        )?;
+
+        {
+            let mut stmt =
+                db_tx.prepare("SELECT height, hash FROM checkpoints WHERE descriptor_id = ?1")?;
+            let mut rows = stmt.query([descriptor_id])?;
+            while let Some(row) = rows.next()? {
+                let hash = BlockHash::from_str(&row.get::<_, String>(1)?)?;
+                changeset
+                    .new_checkpoints
+                    .insert(row.get(0)?, (hash, Default::default()));
+            }
+
+            let mut stmt = db_tx.prepare(
+                "SELECT txid, tx, fee, feerate_sat_per_kvb, confirmation_height, confirmation_time,
+                     checkpoint_height, is_coinbase, first_seen, last_seen
+                 FROM txs WHERE descriptor_id = ?1",
+            )?;
+            let mut rows = stmt.query([descriptor_id])?;
+            while let Some(row) = rows.next()? {
+                let txid = Txid::from_str(&row.get::<_, String>(0)?)?;
+                let tx: Transaction = consensus::deserialize(&row.get::<_, Vec<u8>>(1)?)?;
+                let confirmation_height: Option<u32> = row.get(4)?;
+                let confirmation_time: Option<i64> = row.get(5)?;
+                let checkpoint_height: Option<u32> = row.get(6)?;
+
+                if let Some((_, txids)) =
+                    checkpoint_height.and_then(|height| changeset.new_checkpoints.get_mut(&height))
+                {
+                    txids.insert(txid);
+                }
+
+                changeset.added_txs.insert(
+                    txid,
+                    AugmentedTx {
+                        tx,
+                        fee: row.get::<_, i64>(2)? as u64,
+                        feerate: FeeRate::from_sat_per_kvb(row.get::<_, i64>(3)? as u64),
+                        confirmation_time: confirmation_height.zip(confirmation_time).map(
+                            |(height, time)| BlockTime {
+                                height,
+                                time: time as u64,
+                            },
+                        ),
+                        is_coinbase: row.get(7)?,
+                        first_seen: row.get::<_, Option<i64>>(8)?.map(|time| time as u64),
+                        last_seen: row.get::<_, Option<i64>>(9)?.map(|time| time as u64),
+                    },
+                );
+            }
+
+            let mut stmt =
+                db_tx.prepare("SELECT txid, replaced_by FROM evicted WHERE descriptor_id = ?1")?;
+            let mut rows = stmt.query([descriptor_id])?;
+            while let Some(row) = rows.next()? {
+                changeset.evicted_txs.insert(
+                    Txid::from_str(&row.get::<_, String>(0)?)?,
+                    Txid::from_str(&row.get::<_, String>(1)?)?,
+                );
+            }
+
+            let mut stmt = db_tx.prepare(
+                "SELECT txid, vout, value, derivation_index, spent_by_txid, spent_by_vin
+                 FROM txouts WHERE descriptor_id = ?1",
+            )?;
+            let mut rows = stmt.query([descriptor_id])?;
+            while let Some(row) = rows.next()? {
+                let outpoint = OutPoint {
+                    txid: Txid::from_str(&row.get::<_, String>(0)?)?,
+                    vout: row.get(1)?,
+                };
+                let spent_by = match row.get::<_, Option<String>>(4)? {
+                    Some(txid) => Some((row.get(5)?, Txid::from_str(&txid)?)),
+                    None => None,
+                };
+                stored_txouts.insert(
+                    outpoint,
+                    (row.get::<_, i64>(2)? as u64, row.get(3)?, spent_by),
+                );
+            }
+        }
+
+        db_tx.commit()?;
+
+        let mut loaded = tracker.clone();
+        loaded.apply_changeset(changeset);
+        let derived_txouts = loaded
+            .iter_txout()
+            .map(|txout| {
+                (
+                    txout.outpoint,
+                    (txout.value, txout.derivation_index, txout.spent_by),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        if let Some(outpoint) = stored_txouts
+            .keys()
+            .chain(derived_txouts.keys())
+            .find(|outpoint| stored_txouts.get(outpoint) != derived_txouts.get(outpoint))
+        {
+            return Err(Error::InconsistentTxOut {
+                outpoint: *outpoint,
+            });
+        }
+        *tracker = loaded;
+        Ok(())
+    }
+
+    /// The underlying connection for running your own queries against the wallet state
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+fn descriptor_id(db_tx: &DbTransaction, tracker: &DescriptorTracker) -> Result<Option<i64>, Error> {
+    Ok(db_tx
+        .query_row(
+            "SELECT id FROM descriptors WHERE descriptor = ?1",
+            [tracker.descriptor().to_string()],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    Ok(conn
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?
+        .unwrap_or(0))
+}
+
+fn migrate(db_tx: &DbTransaction) -> Result<(), Error> {
+    let version = schema_version(db_tx)?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(Error::UnknownSchemaVersion { version });
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        db_tx.execute_batch(migration)?;
+    }
+
+    db_tx.execute("DELETE FROM schema_version", [])?;
+    db_tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        [MIGRATIONS.len() as u32],
+    )?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// A hash stored in the database is not valid hex
+    Hex(hex::Error),
+    /// A transaction stored in the database failed to decode
+    Consensus(consensus::encode::Error),
+    /// The database was created by a newer version of this crate
+    UnknownSchemaVersion {
+        version: u32,
+    },
+    /// The stored txout doesn't match the txouts of the stored txs
+    InconsistentTxOut {
+        outpoint: OutPoint,
+    },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::Hex(e) => write!(f, "{}", e),
+            Error::Consensus(e) => write!(f, "{}", e),
+            Error::UnknownSchemaVersion { version } => {
+                write!(
+                    f,
+                    "The database schema version {} is not supported",
+                    version
+                )
+            }
+            Error::InconsistentTxOut { outpoint } => {
+                write!(
+                    f,
+                    "The stored txout {} doesn't match the stored txs",
+                    outpoint
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<hex::Error> for Error {
+    fn from(e: hex::Error) -> Self {
+        Error::Hex(e)
+    }
+}
+
+impl From<consensus::encode::Error> for Error {
+    fn from(e: consensus::encode::Error) -> Self {
+        Error::Consensus(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk_core::{
+        bitcoin::{hashes::Hash, TxIn, TxOut},
+        CheckPoint, PrevOuts, Update, UpdateResult,
+    };
+
+    const DESCRIPTOR: &str = "wpkh(xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL)";
+
+    fn new_tracker() -> DescriptorTracker {
+        DescriptorTracker::new(DESCRIPTOR.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut tracker = new_tracker();
+        tracker.set_lookahead(5);
+        let script_pubkey = tracker.derive_new().0 .1.clone();
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let spend = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: coinbase.txid(),
+                    vout: 0,
+                },
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 40_000,
+                script_pubkey,
+            }],
+        };
+        let spend_txid = spend.txid();
+        let update = Update {
+            transactions: vec![
+                (
+                    PrevOuts::Coinbase,
+                    coinbase.clone(),
+                    Some(BlockTime {
+                        height: 1,
+                        time: 100,
+                    }),
+                    None,
+                ),
+                (
+                    PrevOuts::Spend(coinbase.output.clone()),
+                    spend,
+                    None,
+                    Some(200),
+                ),
+            ],
+            last_active_index: Some(0),
+            mempool_is_total_set: true,
+            base_tip: None,
+            invalidate: Vec::new(),
+            new_tip: CheckPoint {
+                height: 2,
+                hash: BlockHash::default(),
+            },
+        };
+        assert!(matches!(
+            tracker.apply_update(update),
+            Ok(UpdateResult::Ok(_))
+        ));
+
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len() as u32);
+        store.save(&tracker).unwrap();
+        // saving twice replaces the previous state
+        store.save(&tracker).unwrap();
+
+        let mut loaded = new_tracker();
+        store.load_into_tracker(&mut loaded).unwrap();
+        assert_eq!(
+            loaded.iter_tx().collect::<Vec<_>>(),
+            tracker.iter_tx().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.iter_txout().collect::<Vec<_>>(),
+            tracker.iter_txout().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.iter_checkpoints().collect::<Vec<_>>(),
+            tracker.iter_checkpoints().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.next_derivation_index(),
+            tracker.next_derivation_index()
+        );
+        assert_eq!(loaded.lookahead(), 5);
+
+        let spent_by: Option<String> = store
+            .connection()
+            .query_row(
+                "SELECT spent_by_txid FROM txouts WHERE txid = ?1",
+                [coinbase.txid().to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(spent_by.is_some());
+        let mempool: Vec<String> = store
+            .connection()
+            .prepare("SELECT txid FROM mempool")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(mempool, vec![spend_txid.to_string()]);
+
+        // a txout that doesn't match the txs is rejected
+        store
+            .connection()
+            .execute(
+                "UPDATE txouts SET value = value + 1 WHERE spent_by_txid IS NULL",
+                [],
+            )
+            .unwrap();
+        let mut loaded = new_tracker();
+        assert!(matches!(
+            store.load_into_tracker(&mut loaded),
+            Err(Error::InconsistentTxOut { outpoint }) if outpoint.txid == spend_txid
+        ));
+        assert_eq!(loaded.iter_tx().count(), 0);
+    }
+
+    #[test]
+    fn state_survives_reopen_after_eviction_and_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wallet.sqlite");
+        let mut tracker = new_tracker();
+        let script_pubkey = tracker.derive_new().0 .1.clone();
+        let pay = |previous_output, lock_time| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let parent = pay(OutPoint::new(Txid::default(), 0), 0);
+        let parent_txo = OutPoint::new(parent.txid(), 0);
+        let (spend_a, spend_b) = (pay(parent_txo, 0), pay(parent_txo, 1));
+        let spend = |tx: &Transaction, seen_at| {
+            (
+                PrevOuts::Spend(parent.output.clone()),
+                tx.clone(),
+                None,
+                Some(seen_at),
+            )
+        };
+        let confirm = |tx: &Transaction, height| {
+            (
+                PrevOuts::Spend(vec![TxOut::default()]),
+                tx.clone(),
+                Some(BlockTime {
+                    height,
+                    time: height as u64,
+                }),
+                None,
+            )
+        };
+        let checkpoint = |height, hash_byte| CheckPoint {
+            height,
+            hash: BlockHash::from_slice(&[hash_byte; 32]).unwrap(),
+        };
+
+        let updates = vec![
+            (
+                vec![confirm(&parent, 1), spend(&spend_a, 100)],
+                vec![],
+                checkpoint(1, 1),
+            ),
+            // spend_b replaces spend_a
+            (vec![spend(&spend_b, 200)], vec![], checkpoint(2, 2)),
+            // the block with the parent is reorged out and it's confirmed in another one
+            (
+                vec![confirm(&parent, 2), spend(&spend_b, 300)],
+                vec![checkpoint(1, 1)],
+                checkpoint(2, 3),
+            ),
+        ];
+        for (transactions, invalidate, new_tip) in updates {
+            let update = Update {
+                transactions,
+                last_active_index: Some(0),
+                mempool_is_total_set: false,
+                base_tip: if invalidate.is_empty() {
+                    tracker.latest_checkpoint()
+                } else {
+                    None
+                },
+                invalidate,
+                new_tip,
+            };
+            assert!(matches!(
+                tracker.apply_update(update),
+                Ok(UpdateResult::Ok(_))
+            ));
+
+            SqliteStore::open(&path).unwrap().save(&tracker).unwrap();
+            let mut loaded = new_tracker();
+            SqliteStore::open(&path)
+                .unwrap()
+                .load_into_tracker(&mut loaded)
+                .unwrap();
+            assert_eq!(
+                loaded.iter_tx().collect::<Vec<_>>(),
+                tracker.iter_tx().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                loaded.iter_txout().collect::<Vec<_>>(),
+                tracker.iter_txout().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                loaded.iter_checkpoints().collect::<Vec<_>>(),
+                tracker.iter_checkpoints().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                loaded.iter_evicted().collect::<Vec<_>>(),
+                tracker.iter_evicted().collect::<Vec<_>>()
+            );
+            assert_eq!(loaded.latest_blockheight(), tracker.latest_blockheight());
+        }
+        assert_eq!(tracker.replaced_by(spend_a.txid()), Some(spend_b.txid()));
+        assert_eq!(
+            tracker
+                .iter_checkpoints()
+                .map(|(cp, _)| cp)
+                .collect::<Vec<_>>(),
+            vec![checkpoint(2, 3)]
+        );
+    }
+
+    #[test]
+    fn migrates_feerate_to_sat_per_kvb() {
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut::default()],
+        };
+        let mut conn = Connection::open_in_memory().unwrap();
+        let db_tx = conn.transaction().unwrap();
+        schema_version(&db_tx).unwrap();
+        for migration in &MIGRATIONS[..3] {
+            db_tx.execute_batch(migration).unwrap();
+        }
+        db_tx
+            .execute("INSERT INTO schema_version (version) VALUES (3)", [])
+            .unwrap();
+        db_tx
+            .execute(
+                "INSERT INTO descriptors (descriptor, next_derivation_index) VALUES (?1, 0)",
+                [new_tracker().descriptor().to_string()],
+            )
+            .unwrap();
+        db_tx
+            .execute(
+                "INSERT INTO txs (descriptor_id, txid, tx, fee, feerate) VALUES (1, ?1, ?2, 0, 2.345)",
+                params![tx.txid().to_string(), consensus::serialize(&tx)],
+            )
+            .unwrap();
+        db_tx.commit().unwrap();
+
+        let mut store = SqliteStore::from_connection(conn).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len() as u32);
+        let mut loaded = new_tracker();
+        store.load_into_tracker(&mut loaded).unwrap();
+        assert_eq!(
+            loaded.get_tx(tx.txid()).unwrap().feerate,
+            FeeRate::from_sat_per_kvb(2_345)
+        );
+    }
+}